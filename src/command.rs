@@ -1,8 +1,19 @@
-use std::fs;
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
-use crate::{dir::Directories, matrix::MatrixClient, Error, Result};
+use crate::{
+    config::Config,
+    dir::Directories,
+    matrix::{self, ClientOptions, Homeserver, MatrixClient},
+    output::{self, OutputFormat},
+    Error, Result,
+};
 
-use url::Url;
+use matrix_sdk::ruma::{OwnedDeviceId, UserId};
+
+use atty::Stream;
 
 use clap::Parser;
 
@@ -21,13 +32,38 @@ pub(crate) enum Command {
 }
 
 impl Command {
-    pub(super) async fn run(self, client: Result<MatrixClient>, dirs: &Directories) -> Result {
+    pub(super) async fn run(
+        self,
+        client: Result<MatrixClient>,
+        dirs: &Directories,
+        config: &Config,
+        options: &ClientOptions,
+        output: OutputFormat,
+    ) -> Result {
         match self {
-            Self::Login(command) => command.run(client, dirs).await,
+            Self::Login(_) | Self::Logout(_) if options.dry_run => {
+                Error::custom("This command can't be used with --dry-run")
+            }
+            Self::Login(command) => command.run(client, dirs, config, options).await,
             Self::Logout(command) => command.run(client, dirs).await,
             Self::LoggedInCommands(command) => {
-                let client = client?;
-                command.run(client).await
+                let client = match client {
+                    // stdin may hold the Message, so only ask a terminal for the Password
+                    Err(Error::SessionExpired)
+                        if options.force_relogin && atty::is(Stream::Stdin) =>
+                    {
+                        if !output::quiet() {
+                            eprintln!("Session expired, logging in again");
+                        }
+                        let password = rpassword::prompt_password("Password: ")?;
+                        MatrixClient::relogin(dirs, options, &password).await?
+                    }
+                    client => client?,
+                };
+                command
+                    .run(client, config, output)
+                    .await
+                    .map_err(Error::or_session_expired)
             }
         }
     }
@@ -35,38 +71,111 @@ impl Command {
 
 #[derive(Debug, Parser)]
 pub(crate) struct LoginCommand {
-    /// Homeserver Url
-    homeserver: Url,
+    /// Homeserver Url, server name or full User ID (then also used as Username)
+    ///
+    /// Defaults to the homeserver in config.json
+    homeserver: Option<Homeserver>,
 
-    /// Matrix Account Username
+    /// Matrix Account Username (full User ID with --token)
     username: Option<String>,
 
     /// Matrix Account Password
     password: Option<String>,
+
+    /// Read the Password from this environment variable
+    #[clap(long, value_name = "VARNAME", conflicts_with = "password")]
+    password_env: Option<String>,
+
+    /// Read the Password from this file (should be mode 600)
+    #[clap(long, conflicts_with_all = &["password", "password-env"])]
+    password_file: Option<PathBuf>,
+
+    /// Login with an existing Access Token instead of a Password
+    #[clap(long, requires = "device-id", conflicts_with = "password")]
+    token: Option<String>,
+
+    /// Device ID belonging to the Access Token
+    #[clap(long, requires = "token")]
+    device_id: Option<OwnedDeviceId>,
+
+    /// Display name of the new device (Defaults to the app name)
+    #[clap(long, conflicts_with = "token")]
+    device_name: Option<String>,
+
+    /// Skip checking that the homeserver is reachable before logging in
+    #[clap(long)]
+    no_preflight: bool,
+
+    /// Login with Single Sign-On in the browser
+    #[clap(
+        long,
+        conflicts_with_all = &["username", "password", "password-env", "password-file", "token"]
+    )]
+    sso: bool,
 }
 
 impl LoginCommand {
-    async fn run(self, client: Result<MatrixClient>, dirs: &Directories) -> Result {
+    async fn run(
+        self,
+        client: Result<MatrixClient>,
+        dirs: &Directories,
+        config: &Config,
+        options: &ClientOptions,
+    ) -> Result {
         if client.is_ok() {
             Error::custom("Already logged in")
         } else {
-            let username = self
-                .username
-                .map_or_else(|| Self::user_input("Username:"), Ok)?;
-            let password = self
-                .password
-                .map_or_else(|| Self::user_input("Password:"), Ok)?;
-            MatrixClient::login(dirs, &self.homeserver, username.trim(), password.trim()).await?;
+            let homeserver = match (self.homeserver, &config.homeserver) {
+                (Some(homeserver), _) => homeserver,
+                (None, Some(homeserver)) => homeserver.parse().map_err(Error::Custom)?,
+                (None, None) => return Error::custom("No homeserver given"),
+            };
+            if !self.no_preflight {
+                MatrixClient::check_homeserver(&homeserver, options).await?;
+            }
+            if self.sso {
+                MatrixClient::login_sso(dirs, options, &homeserver, self.device_name.as_deref())
+                    .await?;
+                return Ok(());
+            }
+            let username = match (self.username, homeserver.user_id()) {
+                (Some(username), _) => username,
+                (None, Some(user_id)) => user_id.to_string(),
+                (None, None) => user_input("Username:")?,
+            };
+            if let (Some(token), Some(device_id)) = (self.token, self.device_id) {
+                let user_id =
+                    UserId::parse(username.trim()).map_err(|_| Error::Custom("Invalid User ID"))?;
+                MatrixClient::login_with_token(
+                    dirs,
+                    options,
+                    &homeserver,
+                    user_id,
+                    token,
+                    device_id,
+                )
+                .await?;
+                return Ok(());
+            }
+            let password = if let Some(var) = self.password_env {
+                env::var(&var).map_err(|_| Error::MissingEnvVar(var))?
+            } else if let Some(path) = self.password_file {
+                read_password_file(&path)?
+            } else {
+                self.password.map_or_else(password_input, Ok)?
+            };
+            MatrixClient::login(
+                dirs,
+                options,
+                &homeserver,
+                username.trim(),
+                password.trim(),
+                self.device_name.as_deref(),
+            )
+            .await?;
             Ok(())
         }
     }
-
-    fn user_input(message: &'static str) -> Result<String> {
-        println!("{}", message);
-        let mut line = String::new();
-        std::io::stdin().read_line(&mut line)?;
-        Ok(line)
-    }
 }
 
 #[derive(Debug, Parser)]
@@ -85,3 +194,64 @@ impl LogoutCommand {
         Ok(())
     }
 }
+
+fn user_input(message: &'static str) -> Result<String> {
+    if !output::quiet() {
+        println!("{}", message);
+    }
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line)
+}
+
+/// Prompt for the Password without echoing it, if stdin is a terminal
+fn password_input() -> Result<String> {
+    if atty::is(Stream::Stdin) {
+        if !output::quiet() {
+            println!("Password:");
+        }
+        Ok(rpassword::read_password()?)
+    } else {
+        user_input("Password:")
+    }
+}
+
+/// Account Password for servers asking to confirm a change, prompted for if not given
+#[derive(Debug, Parser)]
+pub(crate) struct PasswordOptions {
+    /// Read the Password from this environment variable
+    #[clap(long, value_name = "VARNAME")]
+    password_env: Option<String>,
+
+    /// Read the Password from this file (should be mode 600)
+    #[clap(long, conflicts_with = "password-env")]
+    password_file: Option<PathBuf>,
+}
+
+impl PasswordOptions {
+    pub(crate) fn password(self) -> Result<String> {
+        if let Some(var) = self.password_env {
+            env::var(&var).map_err(|_| Error::MissingEnvVar(var))
+        } else if let Some(path) = self.password_file {
+            read_password_file(&path)
+        } else {
+            password_input()
+        }
+    }
+}
+
+fn read_password_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    if matrix::is_world_readable(&file)? {
+        eprintln!("Warning: {} is world-readable", path.display());
+    }
+    let mut password = String::new();
+    file.read_to_string(&mut password)?;
+    if password.ends_with('\n') {
+        password.pop();
+        if password.ends_with('\r') {
+            password.pop();
+        }
+    }
+    Ok(password)
+}
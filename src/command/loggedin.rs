@@ -1,19 +1,81 @@
-use crate::{matrix::MatrixClient, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{
+    config::Config,
+    matrix::MatrixClient,
+    output::{self, print_message, OutputFormat},
+    progress::UploadSpinner,
+    Error, Result,
+};
 
 use clap::Parser;
 
+use matrix_sdk::{
+    config::SyncSettings,
+    room::Room,
+    ruma::{
+        api::client::{filter::FilterDefinition, sync::sync_events::v3::Filter},
+        events::room::message::{OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+        EventId, OwnedEventId, OwnedRoomOrAliasId, UInt,
+    },
+    Client,
+};
+
+use mime::Mime;
+
+use serde_json::json;
+
+use tokio::time;
+
+mod account;
+mod devices;
 mod room;
 
 #[derive(Debug, Parser)]
 pub(crate) enum Command {
     /// Room Subcommands
     Room(RoomCommand),
+
+    /// Account Subcommands
+    Account(AccountCommand),
+
+    /// Show the logged in User
+    Whoami(WhoamiCommand),
+
+    /// Device Subcommands
+    Devices(DevicesCommand),
+
+    /// Print incoming Messages until interrupted
+    Tail(TailCommand),
+
+    /// Upload a file without sending it and print its mxc:// URI
+    Upload(UploadCommand),
+
+    /// Measure the round-trip time of Messages sent to a Room
+    Ping(PingCommand),
 }
 
 impl Command {
-    pub(super) async fn run(self, client: MatrixClient) -> Result {
+    pub(super) async fn run(
+        self,
+        client: MatrixClient,
+        config: &Config,
+        output: OutputFormat,
+    ) -> Result {
         match self {
-            Self::Room(command) => command.run(client).await,
+            Self::Room(command) => command.run(client, config, output).await,
+            Self::Account(command) => command.run(client, output).await,
+            Self::Whoami(command) => command.run(client, output).await,
+            Self::Devices(command) => command.run(client, output).await,
+            Self::Tail(command) => command.run(client, output).await,
+            Self::Upload(command) => command.run(client, output).await,
+            Self::Ping(command) => command.run(client, output).await,
         }
     }
 }
@@ -25,7 +87,330 @@ pub(crate) struct RoomCommand {
 }
 
 impl RoomCommand {
-    async fn run(self, client: MatrixClient) -> Result {
-        self.command.run(client).await
+    async fn run(self, client: MatrixClient, config: &Config, output: OutputFormat) -> Result {
+        self.command.run(client, config, output).await
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct AccountCommand {
+    #[clap(subcommand)]
+    command: account::Command,
+}
+
+impl AccountCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        self.command.run(client, output).await
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct DevicesCommand {
+    #[clap(subcommand)]
+    command: devices::Command,
+}
+
+impl DevicesCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        self.command.run(client, output).await
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct WhoamiCommand {}
+
+impl WhoamiCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        let user_id = client.user_id().await.ok_or(Error::NotLoggedIn)?;
+        let device_id = client.device_id().await.ok_or(Error::NotLoggedIn)?;
+        let homeserver = client.homeserver().await;
+        match output {
+            OutputFormat::Text => {
+                println!("{}\t{}\t{}", user_id, device_id, homeserver);
+            }
+            OutputFormat::Json => println!(
+                "{}",
+                json!({
+                    "user_id": user_id,
+                    "device_id": device_id,
+                    "homeserver": homeserver,
+                })
+            ),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct TailCommand {
+    /// Only print Messages from this Room
+    #[clap(long)]
+    room: Option<OwnedRoomOrAliasId>,
+}
+
+impl TailCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        let filter = match &self.room {
+            Some(room) => Some(client.resolve_room(room).await?),
+            None => None,
+        };
+        client
+            .register_event_handler(move |event: OriginalSyncRoomMessageEvent, room: Room| {
+                let filter = filter.clone();
+                async move {
+                    if filter.is_none_or(|filter| filter == room.room_id()) {
+                        print_message(
+                            room.room_id(),
+                            &event.sender,
+                            event.origin_server_ts,
+                            &event.content,
+                            output,
+                        );
+                    }
+                }
+            })
+            .await;
+
+        let mut settings = SyncSettings::new();
+        if let Some(token) = client.sync_token().await {
+            settings = settings.token(token);
+        }
+        tokio::select! {
+            _ = client.sync(settings) => {}
+            _ = tokio::signal::ctrl_c() => {
+                if !output::quiet() {
+                    eprintln!("Interrupted, stopping");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct UploadCommand {
+    /// File Path
+    file: PathBuf,
+
+    /// Override auto detected mime type
+    #[clap(long)]
+    mime: Option<Mime>,
+}
+
+impl UploadCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        if !self.file.is_file() {
+            return Err(Error::InvalidFile);
+        }
+        let mime = self.mime.unwrap_or_else(|| {
+            mime_guess::from_path(&self.file).first_or(mime::APPLICATION_OCTET_STREAM)
+        });
+        let reader = File::open(&self.file)?;
+        let len = reader.metadata()?.len();
+        if client.dry_run() {
+            match output {
+                OutputFormat::Text => println!("{}\t{}\t{}", self.file.display(), mime, len),
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "file": self.file,
+                        "mimetype": mime.as_ref(),
+                        "size": len,
+                    })
+                ),
+            }
+            return Ok(());
+        }
+        let spinner = UploadSpinner::new(self.file.display().to_string());
+        let (client, file, mime) = (&client, &self.file, &mime);
+        // the file is opened again for every attempt
+        let content_uri = client
+            .rate_limited(move || async move {
+                let mut reader = BufReader::new(File::open(file)?);
+                client.upload(mime, &mut reader).await
+            })
+            .await?
+            .content_uri;
+        drop(spinner);
+        match output {
+            OutputFormat::Text => println!("{}", content_uri),
+            OutputFormat::Json => println!("{}", json!({ "content_uri": content_uri })),
+        }
+        Ok(())
+    }
+}
+
+/// When Messages came in through sync
+#[derive(Clone, Default)]
+struct Arrivals(Arc<Mutex<HashMap<OwnedEventId, Instant>>>);
+
+impl Arrivals {
+    /// Start recording the Messages received by following syncs
+    async fn register(client: &MatrixClient) -> Self {
+        let arrivals = Self::default();
+        let map = arrivals.0.clone();
+        client
+            .register_event_handler(move |event: OriginalSyncRoomMessageEvent| {
+                let map = map.clone();
+                async move {
+                    if let Ok(mut map) = map.lock() {
+                        map.entry(event.event_id).or_insert_with(Instant::now);
+                    }
+                }
+            })
+            .await;
+        arrivals
+    }
+
+    fn get(&self, event_id: &EventId) -> Option<Instant> {
+        self.0.lock().ok()?.get(event_id).copied()
+    }
+
+    /// Sync until all `event_ids` arrived, false on timeout
+    ///
+    /// Sync errors are returned as they are, instead of trying again right away.
+    async fn wait_for(
+        &self,
+        client: &MatrixClient,
+        event_ids: &[OwnedEventId],
+        timeout: Duration,
+    ) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        // the server's default timeline limit could cut off some of the awaited Messages
+        let mut filter = FilterDefinition::default();
+        filter.room.timeline.limit = Some(UInt::new_saturating(event_ids.len() as u64));
+        loop {
+            if event_ids
+                .iter()
+                .all(|event_id| self.get(event_id).is_some())
+            {
+                return Ok(true);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+            let mut settings = SyncSettings::new()
+                .timeout(remaining)
+                .filter(Filter::FilterDefinition(filter.clone()));
+            if let Some(token) = client.sync_token().await {
+                settings = settings.token(token);
+            }
+            match time::timeout(remaining, Client::sync_once(client, settings)).await {
+                Ok(response) => {
+                    response?;
+                }
+                Err(_) => return Ok(false),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct PingCommand {
+    /// Room ID or Alias
+    room: OwnedRoomOrAliasId,
+
+    /// Number of pings to send
+    #[clap(long, default_value_t = 1)]
+    count: u32,
+
+    /// Seconds to wait for each ping to come back
+    #[clap(long, default_value_t = 30)]
+    timeout: u64,
+
+    /// Redact the ping Messages afterwards
+    #[clap(long)]
+    cleanup: bool,
+}
+
+impl PingCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        if self.count == 0 {
+            return Error::custom("--count must be at least 1");
+        }
+        client.refuse_dry_run()?;
+        let room = client.sendable_room_by_id_or_alias(&self.room).await?;
+        let timeout = Duration::from_secs(self.timeout);
+
+        let arrivals = Arrivals::register(&client).await;
+        // Start from the current position, so only new Messages are seen
+        client.sync_once().await?;
+
+        let mut event_ids = Vec::new();
+        let mut samples = Vec::new();
+        let mut result = Ok(());
+        for seq in 1..=self.count {
+            let content = RoomMessageEventContent::notice_plain(format!("ping {}", seq));
+            let sent = Instant::now();
+            let event_id = match client
+                .rate_limited(|| room.send(content.clone(), None))
+                .await
+            {
+                Ok(response) => response.event_id,
+                Err(e) => {
+                    result = Err(e.into());
+                    break;
+                }
+            };
+            event_ids.push(event_id.clone());
+
+            let rtt = match arrivals
+                .wait_for(&client, slice::from_ref(&event_id), timeout)
+                .await
+            {
+                Ok(true) => {
+                    let arrived = arrivals.get(&event_id).unwrap_or_else(Instant::now);
+                    arrived.duration_since(sent).as_secs_f64() * 1000.0
+                }
+                Ok(false) => {
+                    result = Error::custom("Ping timed out");
+                    break;
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            samples.push(rtt);
+            match output {
+                OutputFormat::Text => println!("{}\t{}\t{:.1} ms", seq, event_id, rtt),
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({ "seq": seq, "event_id": event_id, "rtt_ms": rtt })
+                ),
+            }
+        }
+
+        if !samples.is_empty() {
+            let min = samples.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = samples.iter().copied().fold(0.0, f64::max);
+            let avg = samples.iter().sum::<f64>() / samples.len() as f64;
+            match output {
+                OutputFormat::Text => {
+                    if !output::quiet() {
+                        println!("min/avg/max = {:.1}/{:.1}/{:.1} ms", min, avg, max);
+                    }
+                }
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "count": samples.len(),
+                        "min_ms": min,
+                        "avg_ms": avg,
+                        "max_ms": max,
+                    })
+                ),
+            }
+        }
+
+        if self.cleanup {
+            for event_id in &event_ids {
+                client
+                    .rate_limited(|| room.redact(event_id, Some("ping"), None))
+                    .await?;
+            }
+        }
+        result
     }
 }
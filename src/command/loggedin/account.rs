@@ -0,0 +1,31 @@
+use crate::{matrix::MatrixClient, output::OutputFormat, Result};
+
+use clap::Parser;
+
+mod threepid;
+
+#[derive(Debug, Parser)]
+pub(crate) enum Command {
+    /// Third-party identifier (email/phone) commands
+    Threepid(ThreepidCommand),
+}
+
+impl Command {
+    pub(super) async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        match self {
+            Self::Threepid(command) => command.run(client, output).await,
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct ThreepidCommand {
+    #[clap(subcommand)]
+    command: threepid::Command,
+}
+
+impl ThreepidCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        self.command.run(client, output).await
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{command::PasswordOptions, matrix::MatrixClient, output::OutputFormat, Error, Result};
+
+use clap::Parser;
+
+use serde_json::json;
+
+use matrix_sdk::ruma::{
+    api::client::{
+        account::{add_3pid, get_3pids, request_3pid_management_token_via_email},
+        uiaa::{AuthData, Password, UserIdentifier},
+    },
+    ClientSecret, OwnedClientSecret, OwnedSessionId, UInt,
+};
+
+#[derive(Debug, Parser)]
+pub(crate) enum Command {
+    /// List third-party identifiers bound to the account
+    List(ListCommand),
+
+    /// Start adding a third-party identifier
+    #[clap(subcommand)]
+    Add(AddCommand),
+
+    /// Finish adding a third-party identifier after it was validated
+    Confirm(ConfirmCommand),
+}
+
+impl Command {
+    pub(super) async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        match self {
+            Self::List(command) => command.run(client, output).await,
+            Self::Add(command) => command.run(client).await,
+            Self::Confirm(command) => command.run(client).await,
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct ListCommand {}
+
+impl ListCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        let response = client.send(get_3pids::v3::Request::new(), None).await?;
+        for threepid in response.threepids {
+            match output {
+                OutputFormat::Text => println!("{}\t{}", threepid.medium, threepid.address),
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "medium": threepid.medium.as_str(),
+                        "address": threepid.address,
+                    })
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) enum AddCommand {
+    /// Add an email address
+    Email {
+        /// Email address
+        address: String,
+    },
+}
+
+impl AddCommand {
+    async fn run(self, client: MatrixClient) -> Result {
+        client.refuse_dry_run()?;
+        match self {
+            Self::Email { address } => {
+                let client_secret = ClientSecret::new();
+                let response = client
+                    .send(
+                        request_3pid_management_token_via_email::v3::Request::new(
+                            &client_secret,
+                            &address,
+                            UInt::from(1_u32),
+                        ),
+                        None,
+                    )
+                    .await?;
+                println!("Verification email sent to {}", address);
+                println!("After following the link in it, run:");
+                println!(
+                    "    {} account threepid confirm {} {}",
+                    crate::APP_NAME,
+                    response.sid,
+                    client_secret
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct ConfirmCommand {
+    /// Session ID printed by `add`
+    sid: OwnedSessionId,
+
+    /// Client secret printed by `add`
+    client_secret: OwnedClientSecret,
+
+    #[clap(flatten)]
+    password: PasswordOptions,
+}
+
+impl ConfirmCommand {
+    async fn run(self, client: MatrixClient) -> Result {
+        client.refuse_dry_run()?;
+        let request = add_3pid::v3::Request::new(&self.client_secret, &self.sid);
+        let session = match client.send(request, None).await {
+            Ok(_) => return Ok(()),
+            Err(e) => match e.uiaa_response() {
+                Some(info) => info.session.clone(),
+                None => return Err(e.into()),
+            },
+        };
+
+        let password = self.password.password()?;
+        let user_id = client.user_id().await.ok_or(Error::NotLoggedIn)?;
+        let mut auth = Password::new(
+            UserIdentifier::UserIdOrLocalpart(user_id.as_str()),
+            password.trim(),
+        );
+        auth.session = session.as_deref();
+
+        let mut request = add_3pid::v3::Request::new(&self.client_secret, &self.sid);
+        request.auth = Some(AuthData::Password(auth));
+        client.send(request, None).await?;
+        Ok(())
+    }
+}
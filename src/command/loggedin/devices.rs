@@ -0,0 +1,99 @@
+use crate::{command::PasswordOptions, matrix::MatrixClient, output::OutputFormat, Error, Result};
+
+use clap::Parser;
+
+use matrix_sdk::ruma::{
+    api::client::uiaa::{AuthData, Password, UserIdentifier},
+    OwnedDeviceId,
+};
+
+use serde_json::json;
+
+#[derive(Debug, Parser)]
+pub(crate) enum Command {
+    /// List devices of the account
+    List(ListCommand),
+
+    /// Delete (log out) a device
+    Delete(DeleteCommand),
+}
+
+impl Command {
+    pub(super) async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        match self {
+            Self::List(command) => command.run(client, output).await,
+            Self::Delete(command) => command.run(client).await,
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct ListCommand {}
+
+impl ListCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        let own_device = client.device_id().await;
+        for device in client.devices().await?.devices {
+            let current = own_device.as_ref() == Some(&device.device_id);
+            let last_seen = device
+                .last_seen_ts
+                .and_then(|ts| ts.to_system_time())
+                .map(|ts| humantime::format_rfc3339_seconds(ts).to_string());
+            match output {
+                OutputFormat::Text => println!(
+                    "{}{}\t{}\t{}",
+                    device.device_id,
+                    if current { " (current)" } else { "" },
+                    last_seen.as_deref().unwrap_or("-"),
+                    device.display_name.as_deref().unwrap_or_default(),
+                ),
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "device_id": device.device_id,
+                        "display_name": device.display_name,
+                        "last_seen": last_seen,
+                        "current": current,
+                    })
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct DeleteCommand {
+    /// Device ID
+    device: OwnedDeviceId,
+
+    #[clap(flatten)]
+    password: PasswordOptions,
+}
+
+impl DeleteCommand {
+    async fn run(self, client: MatrixClient) -> Result {
+        client.refuse_dry_run()?;
+        let devices = [self.device];
+        let session = match client.delete_devices(&devices, None).await {
+            Ok(_) => return Ok(()),
+            Err(e) => match e.uiaa_response() {
+                Some(info) => info.session.clone(),
+                None => return Err(e.into()),
+            },
+        };
+
+        let password = self.password.password()?;
+        let user_id = client.user_id().await.ok_or(Error::NotLoggedIn)?;
+        let mut auth = Password::new(
+            UserIdentifier::UserIdOrLocalpart(user_id.as_str()),
+            password.trim(),
+        );
+        auth.session = session.as_deref();
+
+        client
+            .delete_devices(&devices, Some(AuthData::Password(auth)))
+            .await?;
+        Ok(())
+    }
+}
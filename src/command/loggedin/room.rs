@@ -1,28 +1,79 @@
 use std::borrow::Cow;
-use std::fs::{self, File};
-use std::io::{self, Read};
-use std::path::PathBuf;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, BufReader, Read, Write};
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime};
 
-use crate::{matrix::MatrixClient, Error, Result};
+use super::Arrivals;
+
+use crate::{
+    config::Config,
+    matrix::MatrixClient,
+    output::{self, print_message, OutputFormat},
+    progress::UploadSpinner,
+    ratelimit::{RateLimit, RateLimiter},
+    thumbnail::ImageThumbnail,
+    Error, Result,
+};
 
 use atty::Stream;
 
 use clap::{ArgEnum, ArgGroup, Parser};
 
+use futures_util::{stream, StreamExt};
+
 use matrix_sdk::{
     attachment::AttachmentConfig,
-    room::Room,
+    room::{self, MessagesOptions, Room},
     ruma::{
-        events::room::message::{
-            EmoteMessageEventContent, MessageType, NoticeMessageEventContent,
-            RoomMessageEventContent, TextMessageEventContent,
+        api::client::{
+            error::ErrorKind,
+            membership::{forget_room, leave_room},
+            room::{
+                create_room::v3::{Request as CreateRoomRequest, RoomPreset},
+                Visibility,
+            },
+        },
+        events::{
+            reaction::{ReactionEventContent, Relation},
+            room::encryption::RoomEncryptionEventContent,
+            room::message::{
+                self, AudioInfo, AudioMessageEventContent, EmoteMessageEventContent, FileInfo,
+                FileMessageEventContent, ImageMessageEventContent, InReplyTo, MessageType,
+                NoticeMessageEventContent, Replacement, RoomMessageEventContent,
+                TextMessageEventContent, Thread, VideoInfo, VideoMessageEventContent,
+            },
+            room::name::RoomNameEventContent,
+            room::topic::RoomTopicEventContent,
+            room::ImageInfo,
+            AnyMessageLikeEvent, AnyRoomEvent, MessageLikeEvent,
         },
-        OwnedRoomId, OwnedRoomOrAliasId, OwnedServerName,
+        serde::Raw,
+        EventEncryptionAlgorithm, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedMxcUri,
+        OwnedRoomName, OwnedRoomOrAliasId, OwnedServerName, OwnedUserId, RoomOrAliasId, UInt,
     },
+    HttpError,
 };
 
 use mime::Mime;
 
+use regex::Regex;
+
+use serde::Deserialize;
+
+use serde_json::json;
+
+use tempfile::TempDir;
+
+use tokio::time;
+
+use url::Url;
+
 mod user;
 
 #[derive(Debug, Parser)]
@@ -33,6 +84,9 @@ pub(crate) enum Command {
     /// Leave Room
     Leave(LeaveCommand),
 
+    /// Forget a left Room
+    Forget(ForgetCommand),
+
     /// Send Message into Room
     Send(SendCommand),
 
@@ -44,61 +98,188 @@ pub(crate) enum Command {
 
     /// Send file into room
     SendFile(SendFileCommand),
+
+    /// Redact an event
+    Redact(RedactCommand),
+
+    /// Replace a previously sent Message
+    Edit(EditCommand),
+
+    /// Send an Event of any type from raw JSON content
+    SendRaw(SendRawCommand),
+
+    /// Create Room
+    Create(CreateCommand),
+
+    /// Show Room metadata
+    Info(InfoCommand),
+
+    /// Read the latest Messages of a Room
+    Read(ReadCommand),
+
+    /// Mark Messages as read
+    MarkRead(MarkReadCommand),
+
+    /// Rename Room
+    SetName(SetNameCommand),
+
+    /// Change the Topic of a Room
+    SetTopic(SetTopicCommand),
 }
 
 impl Command {
-    pub(super) async fn run(self, client: MatrixClient) -> Result {
+    pub(super) async fn run(
+        self,
+        client: MatrixClient,
+        config: &Config,
+        output: OutputFormat,
+    ) -> Result {
         match self {
             Self::Join(command) => command.run(client).await,
-            Self::List(command) => command.run(client).await,
-            Self::Send(command) => command.run(client).await,
+            Self::List(command) => command.run(client, output).await,
+            Self::Send(command) => command.run(client, config, output).await,
             Self::Leave(command) => command.run(client).await,
-            Self::User(command) => command.run(client).await,
-            Self::SendFile(command) => command.run(client).await,
+            Self::Forget(command) => command.run(client).await,
+            Self::User(command) => command.run(client, output).await,
+            Self::SendFile(command) => command.run(client, output).await,
+            Self::Redact(command) => command.run(client).await,
+            Self::Edit(command) => command.run(client, output).await,
+            Self::SendRaw(command) => command.run(client, output).await,
+            Self::Create(command) => command.run(client, output).await,
+            Self::Info(command) => command.run(client, output).await,
+            Self::Read(command) => command.run(client, output).await,
+            Self::MarkRead(command) => command.run(client).await,
+            Self::SetName(command) => command.run(client).await,
+            Self::SetTopic(command) => command.run(client).await,
         }
     }
 }
 
 #[derive(Debug, Parser)]
+#[clap(group = ArgGroup::new("and-send-format")
+    .args(&["language", "markdown", "plain", "html", "notice", "emote"])
+    .multiple(true)
+    .requires("and-send"))]
 pub(crate) struct JoinCommand {
     /// Alias or ID of Room
     room: OwnedRoomOrAliasId,
 
-    /// Homeservers used to find the Room
+    /// Homeserver used to find the Room (can be repeated)
+    #[clap(long = "via", value_name = "SERVER")]
     servers: Vec<OwnedServerName>,
+
+    /// Message to send once the room is joined
+    #[clap(long)]
+    and_send: Option<String>,
+
+    #[clap(flatten)]
+    format: MessageFormat,
 }
 
 impl JoinCommand {
     async fn run(self, client: MatrixClient) -> Result {
-        client
-            .join_room_by_id_or_alias(&self.room, &self.servers)
-            .await?;
+        client.refuse_dry_run()?;
+        let (client, room, servers) = (&client, &self.room, &self.servers);
+        let room_id = client
+            .rate_limited(move || async move {
+                client
+                    .join_room_by_id_or_alias(room, servers)
+                    .await
+                    .map_err(matrix_sdk::Error::from)
+            })
+            .await?
+            .room_id;
+        if let Some(msg) = self.and_send {
+            client.wait_for_joined_room(&room_id).await?;
+            let room = &client.sendable_room(&room_id)?;
+            let content = &self.format.content(msg, None);
+            client
+                .rate_limited(move || room.send(content.clone(), None))
+                .await?;
+        }
         Ok(())
     }
 }
 
 #[derive(Debug, Parser)]
 pub(crate) struct LeaveCommand {
-    /// Room ID
-    room: OwnedRoomId,
+    /// Room ID or Alias
+    room: OwnedRoomOrAliasId,
+
+    /// Reason for leaving
+    #[clap(long)]
+    reason: Option<String>,
 }
 
 impl LeaveCommand {
     async fn run(self, client: MatrixClient) -> Result {
-        client.joined_room(&self.room)?.leave().await?;
+        client.refuse_dry_run()?;
+        let room = client.joined_room_by_id_or_alias(&self.room).await?;
+        if let Some(reason) = &self.reason {
+            let mut request = leave_room::v3::Request::new(room.room_id());
+            request.reason = Some(reason);
+            let (client, request) = (&client, &request);
+            client
+                .rate_limited(move || async move {
+                    client
+                        .send(request.clone(), None)
+                        .await
+                        .map_err(matrix_sdk::Error::from)
+                })
+                .await?;
+        } else {
+            client.rate_limited(|| room.leave()).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct ForgetCommand {
+    /// Room ID or Alias
+    room: OwnedRoomOrAliasId,
+}
+
+impl ForgetCommand {
+    async fn run(self, client: MatrixClient) -> Result {
+        client.refuse_dry_run()?;
+        let room_id = client.resolve_room(&self.room).await?;
+        if client.get_joined_room(&room_id).is_some() || client.get_invited_room(&room_id).is_some()
+        {
+            return Error::custom("Leave the Room before forgetting it");
+        }
+        // left Rooms aren't part of the initial sync, so ask the server directly
+        let request = forget_room::v3::Request::new(&room_id);
+        let (client, request) = (&client, &request);
+        client
+            .rate_limited(move || async move {
+                client
+                    .send(request.clone(), None)
+                    .await
+                    .map_err(matrix_sdk::Error::from)
+            })
+            .await
+            .map_err(|e| match &e {
+                matrix_sdk::Error::Http(http)
+                    if matches!(http.client_api_error_kind(), Some(ErrorKind::Unknown)) =>
+                {
+                    Error::Custom("Leave the Room before forgetting it")
+                }
+                _ => e.into(),
+            })?;
         Ok(())
     }
 }
 
 #[derive(Debug, Parser)]
-#[clap(
-    group = ArgGroup::new("msgopt"),
-    group = ArgGroup::new("format"),
-    group = ArgGroup::new("type"),
-)]
+#[clap(group = ArgGroup::new("msgopt"))]
 pub(crate) struct SendCommand {
-    /// Room ID
-    room: OwnedRoomId,
+    /// Room ID or Alias (Defaults to default_room in config.json)
+    room: Option<String>,
+
+    /// Additional rooms to send the same message to
+    #[clap(long, conflicts_with_all = &["reply-to", "thread"])]
+    also_room: Vec<OwnedRoomOrAliasId>,
 
     /// Message to send
     #[clap(group = "msgopt")]
@@ -108,6 +289,367 @@ pub(crate) struct SendCommand {
     #[clap(short, long, group = "msgopt")]
     file: Option<PathBuf>,
 
+    /// Compose Message in $EDITOR
+    #[clap(short, long, group = "msgopt")]
+    edit: bool,
+
+    /// Event ID of the message to reply to
+    #[clap(long)]
+    reply_to: Option<OwnedEventId>,
+
+    /// Event ID of the thread root to post into
+    #[clap(long)]
+    thread: Option<OwnedEventId>,
+
+    #[clap(flatten)]
+    format: MessageFormat,
+
+    /// JSON file with regex rules for reactions added to the sent message
+    #[clap(long)]
+    reaction_rules: Option<PathBuf>,
+
+    /// Write the event id of the sent message to this file
+    #[clap(long)]
+    event_id_file: Option<PathBuf>,
+
+    /// Refuse to send if the content is older than this (e.g. "15m")
+    #[clap(long, requires = "content-timestamp")]
+    max_age: Option<humantime::Duration>,
+
+    /// Only warn instead of refusing stale content
+    #[clap(long, requires = "max-age")]
+    max_age_warn: bool,
+
+    /// Time the content was created (RFC 3339)
+    #[clap(long)]
+    content_timestamp: Option<humantime::Timestamp>,
+
+    /// Limit outgoing events to <N>/<DURATION> (e.g. "5/1s")
+    #[clap(long)]
+    rate_limit: Option<RateLimit>,
+
+    /// Users to mention (adds m.mentions so they get notified)
+    #[clap(long)]
+    mention: Vec<OwnedUserId>,
+
+    /// Show a typing notification while the message is sent
+    #[clap(long)]
+    typing: bool,
+
+    /// Append the sending device id as a footer
+    #[clap(long)]
+    sign: bool,
+
+    /// Include the local hostname in the footer
+    #[clap(long, requires = "sign")]
+    sign_hostname: bool,
+
+    /// Maximum size in bytes of a Message read from a file or stdin
+    #[clap(long, default_value_t = 1024 * 1024)]
+    max_size: u64,
+
+    /// Number of times to retry sending on server errors (and rate limiting if not respected)
+    #[clap(long, default_value_t = 3)]
+    max_retries: u32,
+
+    /// Wait this long before sending (e.g. "10m")
+    #[clap(long, conflicts_with = "at")]
+    delay: Option<humantime::Duration>,
+
+    /// Wait until this time before sending (RFC 3339)
+    #[clap(long)]
+    at: Option<humantime::Timestamp>,
+
+    /// Sync until the sent Messages show up in the Room timeline
+    #[clap(long)]
+    wait_for_delivery: bool,
+
+    /// Split long Messages into several of at most BYTES (Defaults to 30000, at least 1024)
+    #[clap(
+        long,
+        value_name = "BYTES",
+        require_equals = true,
+        value_parser = parse_split_size,
+        conflicts_with = "html"
+    )]
+    split: Option<Option<usize>>,
+}
+
+impl SendCommand {
+    async fn run(mut self, client: MatrixClient, config: &Config, output: OutputFormat) -> Result {
+        // a single positional is the Message if it isn't a room and a default room is configured
+        let (room, message) = match (&self.room, &self.message) {
+            (Some(room), None)
+                if config.default_room.is_some() && RoomOrAliasId::parse(room).is_err() =>
+            {
+                (None, Some(room.clone()))
+            }
+            (room, message) => (room.as_deref(), message.clone()),
+        };
+        let room = match room {
+            Some(room) => RoomOrAliasId::parse(room).map_err(|_| Error::InvalidRoom)?,
+            None => config.default_room.clone().ok_or(Error::Custom(
+                "No room given and no default_room configured",
+            ))?,
+        };
+        if let (Some(max_age), Some(timestamp)) = (self.max_age, self.content_timestamp) {
+            let age = SystemTime::now()
+                .duration_since(*timestamp)
+                .unwrap_or_default();
+            if age > *max_age {
+                if self.max_age_warn {
+                    eprintln!(
+                        "Warning: content is {} old",
+                        humantime::format_duration(Duration::from_secs(age.as_secs()))
+                    );
+                } else {
+                    return Error::custom("Content is older than max age");
+                }
+            }
+        }
+        let rules = self
+            .reaction_rules
+            .as_deref()
+            .map(ReactionRule::load)
+            .transpose()?
+            .unwrap_or_default();
+        let batch = !self.also_room.is_empty();
+        let mut failures = 0;
+        let mut rooms = Vec::new();
+        for room in iter::once(&room).chain(&self.also_room) {
+            match client.sendable_room_by_id_or_alias(room).await {
+                Ok(room) => rooms.push(room),
+                Err(e) if batch => {
+                    eprintln!("{}: {}", room, e);
+                    failures += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        // the message is read only once, stdin can't be consumed again for further rooms
+        let msg = if let Some(msg) = message {
+            msg
+        } else if let Some(file) = &self.file {
+            read_message(File::open(file)?, self.max_size)?
+        } else if self.edit && atty::is(Stream::Stdin) {
+            edit_message()?
+        } else {
+            let mut line = String::new();
+            if atty::is(Stream::Stdin) {
+                if !output::quiet() {
+                    println!("Message:");
+                }
+                io::stdin().read_line(&mut line)?;
+            } else {
+                line = read_message(io::stdin(), self.max_size)?;
+            }
+            line
+        };
+        let reactions: Vec<String> = rules
+            .into_iter()
+            .filter(|(pattern, _)| pattern.is_match(&msg))
+            .map(|(_, reaction)| reaction)
+            .collect();
+        let footer = if self.sign {
+            let device_id = client.device_id().await.ok_or(Error::NotLoggedIn)?;
+            Some(if self.sign_hostname {
+                format!("{} on {}", device_id, hostname::get()?.to_string_lossy())
+            } else {
+                device_id.to_string()
+            })
+        } else {
+            None
+        };
+        let mut limiter = RateLimiter::new(self.rate_limit);
+        let markdown_file = self.file.as_ref().is_some_and(|file| {
+            file.extension()
+                .is_some_and(|ext| ext == "md" || ext == "markdown")
+        });
+        if markdown_file {
+            self.format.default_to_markdown();
+        }
+        let chunks = match self.split {
+            Some(limit) => split_message(&msg, limit.unwrap_or(SPLIT_SIZE)),
+            None => vec![msg],
+        };
+        let last = chunks.len() - 1;
+        let mut contents = Vec::with_capacity(chunks.len());
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            // the footer only goes below the last part
+            let mut content = self
+                .format
+                .content(chunk, footer.as_deref().filter(|_| i == last));
+            if let Some(root) = &self.thread {
+                let thread = match &self.reply_to {
+                    Some(event_id) => Thread::reply(root.clone(), event_id.clone()),
+                    None => Thread::plain(root.clone(), root.clone()),
+                };
+                content.relates_to = Some(message::Relation::Thread(thread));
+            } else if let Some(event_id) = &self.reply_to {
+                content.relates_to = Some(message::Relation::Reply {
+                    in_reply_to: InReplyTo::new(event_id.clone()),
+                });
+            }
+            contents.push(content);
+        }
+        let wait = match (self.delay, self.at) {
+            (Some(delay), _) => Some(*delay),
+            (None, Some(at)) => match at.duration_since(SystemTime::now()) {
+                Ok(wait) => Some(wait),
+                Err(_) => {
+                    eprintln!("Warning: --at is in the past, sending now");
+                    None
+                }
+            },
+            (None, None) => None,
+        };
+        if let Some(wait) = wait.filter(|_| !client.dry_run()) {
+            tokio::select! {
+                _ = time::sleep(wait) => {}
+                _ = tokio::signal::ctrl_c() => return Error::custom("Interrupted before sending"),
+            }
+        }
+        let typing = self.typing && !client.dry_run();
+        if typing {
+            for room in &rooms {
+                let _ = room.typing_notice(true).await;
+            }
+        }
+        let mut event_ids = Vec::new();
+        // run as a block, so typing is turned off again however sending ends
+        let sent = async {
+            for room in &rooms {
+                for (i, content) in contents.iter().enumerate() {
+                    if client.dry_run() {
+                        let content = self.raw_content(content)?;
+                        match output {
+                            OutputFormat::Text => println!("{}\t{}", room.room_id(), content),
+                            OutputFormat::Json => println!(
+                                "{}",
+                                json!({ "room_id": room.room_id(), "content": content })
+                            ),
+                        }
+                        continue;
+                    }
+                    // reactions go to the last part only
+                    let reactions: &[String] = if i == last { &reactions[..] } else { &[] };
+                    let result = self
+                        .send_to(&client, room, content.clone(), reactions, &mut limiter)
+                        .await;
+                    match result {
+                        Ok(event_id) if batch => {
+                            match output {
+                                OutputFormat::Text => println!("{}\t{}", room.room_id(), event_id),
+                                OutputFormat::Json => println!(
+                                    "{}",
+                                    json!({ "room_id": room.room_id(), "event_id": event_id })
+                                ),
+                            }
+                            event_ids.push(event_id);
+                        }
+                        Ok(event_id) => {
+                            print_event_id(&event_id, output);
+                            event_ids.push(event_id);
+                        }
+                        Err(e) if batch => {
+                            eprintln!("{}: {}", room.room_id(), e);
+                            failures += 1;
+                            break;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            Ok::<_, Error>(())
+        }
+        .await;
+        if typing {
+            for room in &rooms {
+                let _ = room.typing_notice(false).await;
+            }
+        }
+        sent?;
+        if let Some(path) = self.event_id_file.as_ref().filter(|_| !client.dry_run()) {
+            write_event_ids(path, &event_ids)?;
+        }
+        if self.wait_for_delivery && !client.dry_run() {
+            let arrivals = Arrivals::register(&client).await;
+            if arrivals
+                .wait_for(&client, &event_ids, DELIVERY_TIMEOUT)
+                .await?
+            {
+                if !output::quiet() {
+                    eprintln!("Delivered");
+                }
+            } else {
+                eprintln!(
+                    "Warning: not all Messages showed up within {}s",
+                    DELIVERY_TIMEOUT.as_secs()
+                );
+            }
+        }
+        if failures > 0 {
+            return Error::custom("Sending failed for some rooms");
+        }
+        Ok(())
+    }
+
+    fn raw_content(&self, content: &RoomMessageEventContent) -> Result<serde_json::Value> {
+        let mut content = serde_json::to_value(content)?;
+        if !self.mention.is_empty() {
+            content["m.mentions"] = json!({ "user_ids": self.mention });
+        }
+        Ok(content)
+    }
+
+    async fn send_to(
+        &self,
+        client: &MatrixClient,
+        room: &room::Joined,
+        content: RoomMessageEventContent,
+        reactions: &[String],
+        limiter: &mut RateLimiter,
+    ) -> Result<OwnedEventId> {
+        if let Some(root) = &self.thread {
+            room.event(root)
+                .await
+                .map_err(|_| Error::Custom("Thread root not found in room"))?;
+        }
+        limiter.acquire().await;
+        let raw = if self.mention.is_empty() {
+            None
+        } else {
+            Some(self.raw_content(&content)?)
+        };
+        let (content, raw) = (&content, &raw);
+        let event_id = with_retries(client, self.max_retries, move || async move {
+            match raw {
+                Some(raw) => room
+                    .send_raw(raw.clone(), "m.room.message", None)
+                    .await
+                    .map(|response| response.event_id),
+                None => room
+                    .send(content.clone(), None)
+                    .await
+                    .map(|response| response.event_id),
+            }
+        })
+        .await?;
+        for reaction in reactions {
+            limiter.acquire().await;
+            let content =
+                ReactionEventContent::new(Relation::new(event_id.clone(), reaction.clone()));
+            client
+                .rate_limited(|| room.send(content.clone(), None))
+                .await?;
+        }
+        Ok(event_id)
+    }
+}
+
+#[derive(Debug, Parser)]
+#[clap(group = ArgGroup::new("format"), group = ArgGroup::new("type"))]
+pub(crate) struct MessageFormat {
     /// Put message in code block
     #[clap(name = "language", long = "code", group = "format")]
     code: Option<Option<String>>,
@@ -116,6 +658,14 @@ pub(crate) struct SendCommand {
     #[clap(long, group = "format")]
     markdown: bool,
 
+    /// Send the Message as literal plain text without formatting
+    #[clap(long, group = "format")]
+    plain: bool,
+
+    /// Send this pre-formatted HTML, using the Message as plain text fallback
+    #[clap(long, group = "format")]
+    html: Option<String>,
+
     /// Send notice
     #[clap(long, group = "type")]
     notice: bool,
@@ -125,61 +675,93 @@ pub(crate) struct SendCommand {
     emote: bool,
 }
 
-impl SendCommand {
-    async fn run(self, client: MatrixClient) -> Result {
-        let msg = if let Some(msg) = self.message {
-            msg
-        } else if let Some(file) = self.file {
-            fs::read_to_string(file)?
-        } else {
-            let mut line = String::new();
-            if atty::is(Stream::Stdin) {
-                println!("Message:");
-                io::stdin().read_line(&mut line)?;
-            } else {
-                io::stdin().read_to_string(&mut line)?;
-            }
-            line
-        };
-        let (msg, markdown) = if let Some(language) = self.code {
-            let mut fmt_msg = String::from("```");
+impl MessageFormat {
+    /// Render as Markdown unless another format was chosen explicitly
+    fn default_to_markdown(&mut self) {
+        if self.code.is_none() && self.html.is_none() && !self.plain {
+            self.markdown = true;
+        }
+    }
+
+    fn content(&self, msg: String, footer: Option<&str>) -> RoomMessageEventContent {
+        let (mut msg, markdown) = if let Some(language) = &self.code {
+            let fence = code_fence(&msg);
+            let mut fmt_msg = fence.clone();
             if let Some(language) = language {
-                fmt_msg.push_str(&language);
+                fmt_msg.push_str(language);
             }
             fmt_msg.push('\n');
             fmt_msg.push_str(&msg);
             if !fmt_msg.ends_with('\n') {
                 fmt_msg.push('\n');
             }
-            fmt_msg.push_str("```");
+            fmt_msg.push_str(&fence);
             (fmt_msg, true)
         } else {
-            (msg, self.markdown)
+            (msg, self.markdown && !self.plain)
         };
+        let mut html = self.html.clone();
+        if let Some(footer) = footer {
+            msg.push_str("\n\n— ");
+            msg.push_str(footer);
+            if let Some(html) = &mut html {
+                html.push_str("<br>— ");
+                html.push_str(footer);
+            }
+        }
         let content = if self.notice {
-            MessageType::Notice(if markdown {
+            MessageType::Notice(if let Some(html) = html {
+                NoticeMessageEventContent::html(msg, html)
+            } else if markdown {
                 NoticeMessageEventContent::markdown(msg)
             } else {
                 NoticeMessageEventContent::plain(msg)
             })
         } else if self.emote {
-            MessageType::Emote(if markdown {
+            MessageType::Emote(if let Some(html) = html {
+                EmoteMessageEventContent::html(msg, html)
+            } else if markdown {
                 EmoteMessageEventContent::markdown(msg)
             } else {
                 EmoteMessageEventContent::plain(msg)
             })
         } else {
-            MessageType::Text(if markdown {
+            MessageType::Text(if let Some(html) = html {
+                TextMessageEventContent::html(msg, html)
+            } else if markdown {
                 TextMessageEventContent::markdown(msg)
             } else {
                 TextMessageEventContent::plain(msg)
             })
         };
-        client
-            .joined_room(&self.room)?
-            .send(RoomMessageEventContent::new(content), None)
-            .await?;
-        Ok(())
+        RoomMessageEventContent::new(content)
+    }
+}
+
+/// Backtick fence longer than any backtick run in `msg`, so it can't be closed early
+fn code_fence(msg: &str) -> String {
+    let longest = msg
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or_default();
+    "`".repeat(longest.max(2) + 1)
+}
+
+#[derive(Deserialize)]
+struct ReactionRule {
+    pattern: String,
+    reaction: String,
+}
+
+impl ReactionRule {
+    /// Load rules of the form `[{"pattern": "error", "reaction": "❌"}]`
+    fn load(path: &Path) -> Result<Vec<(Regex, String)>> {
+        let rules: Vec<Self> = serde_json::from_reader(File::open(path)?)?;
+        rules
+            .into_iter()
+            .map(|rule| Ok((Regex::new(&rule.pattern)?, rule.reaction)))
+            .collect()
     }
 }
 
@@ -188,8 +770,23 @@ pub(crate) struct ListCommand {
     /// Kind
     #[clap(arg_enum, default_value = "joined")]
     kind: Vec<Kind>,
+
+    /// Only list rooms with unread notifications, printing their counts
+    #[clap(long)]
+    unread_only: bool,
+
+    /// Only list rooms whose name contains this (case-insensitive)
+    #[clap(long)]
+    name_contains: Option<String>,
+
+    /// Also list rooms whose name can't be resolved when filtering by name
+    #[clap(long, requires = "name-contains")]
+    include_unnamed: bool,
 }
 
+/// Number of room names resolved at the same time
+const NAME_CONCURRENCY: usize = 10;
+
 #[derive(Clone, ArgEnum, Debug)]
 enum Kind {
     All,
@@ -199,22 +796,70 @@ enum Kind {
 }
 
 impl ListCommand {
-    async fn run(self, client: MatrixClient) -> Result {
-        for room in client.rooms().into_iter().filter(|r| {
-            self.kind.iter().any(|k| {
-                matches!(
-                    (k, r),
-                    (Kind::All, _)
-                        | (Kind::Joined, Room::Joined(_))
-                        | (Kind::Left, Room::Left(_))
-                        | (Kind::Invited, Room::Invited(_))
-                )
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        let rooms = client.rooms().into_iter().filter(|r| {
+            let counts = r.unread_notification_counts();
+            (!self.unread_only || counts.notification_count > 0 || counts.highlight_count > 0)
+                && self.kind.iter().any(|k| {
+                    matches!(
+                        (k, r),
+                        (Kind::All, _)
+                            | (Kind::Joined, Room::Joined(_))
+                            | (Kind::Left, Room::Left(_))
+                            | (Kind::Invited, Room::Invited(_))
+                    )
+                })
+        });
+        // resolve names concurrently, buffered() keeps the output in order
+        let mut rooms = stream::iter(rooms)
+            .map(|room| async move {
+                let name = room.display_name().await.ok();
+                (room, name)
             })
-        }) {
-            if let Ok(name) = room.display_name().await {
-                println!("{}\t{}", room.room_id(), name);
-            } else {
-                println!("{}", room.room_id());
+            .buffered(NAME_CONCURRENCY);
+        while let Some((room, name)) = rooms.next().await {
+            let counts = room.unread_notification_counts();
+            if let Some(filter) = &self.name_contains {
+                match &name {
+                    Some(name) if !name.to_lowercase().contains(&filter.to_lowercase()) => continue,
+                    None if !self.include_unnamed => continue,
+                    _ => {}
+                }
+            }
+            match output {
+                OutputFormat::Text => {
+                    let mut line = room.room_id().to_string();
+                    if self.unread_only {
+                        line = format!(
+                            "{}\t{}\t{}",
+                            line, counts.notification_count, counts.highlight_count
+                        );
+                    }
+                    if let Some(name) = name {
+                        println!("{}\t{}", line, name);
+                    } else {
+                        println!("{}", line);
+                    }
+                }
+                OutputFormat::Json => {
+                    let membership = match room {
+                        Room::Joined(_) => "joined",
+                        Room::Invited(_) => "invited",
+                        Room::Left(_) => "left",
+                    };
+                    let mut value = json!({
+                        "room_id": room.room_id(),
+                        "name": name,
+                        "membership": membership,
+                        "encrypted": room.is_encrypted(),
+                        "member_count": room.joined_members_count(),
+                    });
+                    if self.unread_only {
+                        value["notification_count"] = counts.notification_count.into();
+                        value["highlight_count"] = counts.highlight_count.into();
+                    }
+                    println!("{}", value);
+                }
             }
         }
         Ok(())
@@ -223,54 +868,950 @@ impl ListCommand {
 
 #[derive(Debug, Parser)]
 pub(crate) struct UserCommand {
-    /// Room ID
-    room: OwnedRoomId,
+    /// Room ID or Alias
+    room: OwnedRoomOrAliasId,
 
     #[clap(subcommand)]
     command: user::Command,
 }
 
 impl UserCommand {
-    async fn run(self, client: MatrixClient) -> Result {
-        self.command.run(client, self.room).await
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        let room = client.resolve_room(&self.room).await?;
+        self.command.run(client, room, output).await
     }
 }
 
 #[derive(Debug, Parser)]
 pub(crate) struct SendFileCommand {
-    /// Room ID
-    room: OwnedRoomId,
+    /// Room ID or Alias
+    room: OwnedRoomOrAliasId,
+
+    /// File Paths (http(s) URLs are downloaded first)
+    #[clap(required_unless_present = "mxc")]
+    files: Vec<PathBuf>,
 
-    /// File Path
-    file: PathBuf,
+    /// Send an already uploaded mxc:// URI instead of a file
+    #[clap(long, conflicts_with = "files")]
+    mxc: Option<String>,
 
     /// Override auto detected mime type
     #[clap(long)]
     mime: Option<Mime>,
 
-    /// Override fallback text (Defaults to filename)
+    /// Override fallback text (Defaults to filename, only used for a single file)
     #[clap(long)]
     text: Option<String>,
+
+    /// Write the event ids of the sent messages to this file
+    #[clap(long)]
+    event_id_file: Option<PathBuf>,
+
+    /// Markdown caption sent as a reply to the file (only for a single file)
+    #[clap(long)]
+    caption: Option<String>,
+
+    /// Send the caption as notice
+    #[clap(long, requires = "caption")]
+    caption_notice: bool,
+
+    /// Number of times to retry sending on server errors (and rate limiting if not respected)
+    #[clap(long, default_value_t = 3)]
+    max_retries: u32,
+
+    /// Also post the contents of text files as a reply (Markdown rendered, others as code)
+    #[clap(long)]
+    inline_preview: bool,
+
+    /// Largest file in bytes posted by --inline-preview
+    #[clap(long, default_value_t = 16 * 1024, requires = "inline-preview")]
+    preview_max_size: u64,
 }
 
 impl SendFileCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        if !self
+            .files
+            .iter()
+            .all(|file| file.is_file() || remote_url(file).is_some())
+        {
+            return Err(Error::InvalidFile);
+        }
+        if self.caption.is_some() && self.files.len() > 1 {
+            return Error::custom("A caption can only be sent with a single file");
+        }
+        let room = client.sendable_room_by_id_or_alias(&self.room).await?;
+        let text = self.text.as_ref().filter(|_| self.files.len() == 1);
+        let mut event_ids = Vec::with_capacity(self.files.len());
+        // the caption replies to the attachment, not to its inline preview
+        let mut attachment_event_id = None;
+        if let Some(mxc) = &self.mxc {
+            let url = OwnedMxcUri::from(mxc.as_str());
+            if !url.is_valid() {
+                return Error::custom("Invalid mxc:// URI");
+            }
+            let mime = self.mime.clone().unwrap_or(mime::APPLICATION_OCTET_STREAM);
+            let body = self.text.clone().unwrap_or_else(|| mxc.clone());
+            if client.dry_run() {
+                match output {
+                    OutputFormat::Text => {
+                        println!("{}\t{}\t{}\t{}", room.room_id(), body, mime, url)
+                    }
+                    OutputFormat::Json => println!(
+                        "{}",
+                        json!({
+                            "room_id": room.room_id(),
+                            "body": body,
+                            "mimetype": mime.as_ref(),
+                            "url": url,
+                        })
+                    ),
+                }
+            } else {
+                let content = RoomMessageEventContent::new(mxc_message(body, url, &mime));
+                let event_id = client
+                    .rate_limited(|| room.send(content.clone(), None))
+                    .await?
+                    .event_id;
+                print_event_id(&event_id, output);
+                attachment_event_id = Some(event_id.clone());
+                event_ids.push(event_id);
+            }
+        }
+        for file in &self.files {
+            let download = match remote_url(file) {
+                Some(url) => Some(Download::fetch(&client, &url).await?),
+                None => None,
+            };
+            let file = download.as_ref().map_or(file.as_path(), |d| d.path());
+            let body = text
+                .map(Cow::from)
+                .or_else(|| file.file_name().as_ref().map(|o| o.to_string_lossy()))
+                .ok_or(Error::InvalidFile)?;
+            let mime = self.mime.clone().unwrap_or_else(|| {
+                mime_guess::from_path(file).first_or(mime::APPLICATION_OCTET_STREAM)
+            });
+            let len = fs::metadata(file)?.len();
+            if client.dry_run() {
+                match output {
+                    OutputFormat::Text => {
+                        println!("{}\t{}\t{}\t{}", room.room_id(), body, mime, len)
+                    }
+                    OutputFormat::Json => println!(
+                        "{}",
+                        json!({
+                            "room_id": room.room_id(),
+                            "body": body,
+                            "mimetype": mime.as_ref(),
+                            "size": len,
+                        })
+                    ),
+                }
+                continue;
+            }
+            let (room, body, mime) = (&room, &body, &mime);
+            let spinner = UploadSpinner::new(file.display().to_string());
+            // the file is opened again for every attempt
+            let response = with_retries(&client, self.max_retries, move || async move {
+                let mut reader = File::open(file)?;
+                if let Some(thumbnail) = ImageThumbnail::new(file, mime) {
+                    let mut data = thumbnail.data();
+                    let config = AttachmentConfig::with_thumbnail(thumbnail.thumbnail(&mut data))
+                        .info(thumbnail.info());
+                    room.send_attachment(body, mime, &mut reader, config).await
+                } else {
+                    room.send_attachment(body, mime, &mut reader, AttachmentConfig::new())
+                        .await
+                }
+            })
+            .await?;
+            drop(spinner);
+            let event_id = response.event_id;
+            print_event_id(&event_id, output);
+            attachment_event_id = Some(event_id.clone());
+            event_ids.push(event_id.clone());
+
+            if self.inline_preview && len <= self.preview_max_size {
+                if let Some(mut content) = preview_content(file, mime)? {
+                    content.relates_to = Some(message::Relation::Reply {
+                        in_reply_to: InReplyTo::new(event_id),
+                    });
+                    let event_id = client
+                        .rate_limited(|| room.send(content.clone(), None))
+                        .await?
+                        .event_id;
+                    print_event_id(&event_id, output);
+                    event_ids.push(event_id);
+                }
+            }
+        }
+        if let (Some(caption), Some(file_event_id)) = (&self.caption, attachment_event_id) {
+            let mut content = RoomMessageEventContent::new(if self.caption_notice {
+                MessageType::Notice(NoticeMessageEventContent::markdown(caption))
+            } else {
+                MessageType::Text(TextMessageEventContent::markdown(caption))
+            });
+            content.relates_to = Some(message::Relation::Reply {
+                in_reply_to: InReplyTo::new(file_event_id),
+            });
+            let event_id = client
+                .rate_limited(|| room.send(content.clone(), None))
+                .await?
+                .event_id;
+            print_event_id(&event_id, output);
+            event_ids.push(event_id);
+        }
+        if let Some(path) = self.event_id_file.as_ref().filter(|_| !client.dry_run()) {
+            write_event_ids(path, &event_ids)?;
+        }
+        Ok(())
+    }
+}
+
+/// The contents of a text file as Message, None for binary files
+fn preview_content(file: &Path, mime: &Mime) -> Result<Option<RoomMessageEventContent>> {
+    if mime.type_() != mime::TEXT && mime.subtype() != mime::JSON {
+        return Ok(None);
+    }
+    let text = match String::from_utf8(fs::read(file)?) {
+        Ok(text) => text,
+        Err(_) => return Ok(None),
+    };
+    if matches!(mime.subtype().as_str(), "markdown" | "x-markdown") {
+        return Ok(Some(RoomMessageEventContent::text_markdown(text)));
+    }
+    let fence = code_fence(&text);
+    let language = file
+        .extension()
+        .map(|ext| ext.to_string_lossy())
+        .unwrap_or_default();
+    let mut msg = format!("{}{}\n{}", fence, language, text);
+    if !msg.ends_with('\n') {
+        msg.push('\n');
+    }
+    msg.push_str(&fence);
+    Ok(Some(RoomMessageEventContent::text_markdown(msg)))
+}
+
+/// Message pointing at already uploaded media, typed by its mime type
+fn mxc_message(body: String, url: OwnedMxcUri, mime: &Mime) -> MessageType {
+    let mimetype = Some(mime.to_string());
+    match mime.type_() {
+        mime::IMAGE => {
+            let mut info = ImageInfo::new();
+            info.mimetype = mimetype;
+            MessageType::Image(ImageMessageEventContent::plain(
+                body,
+                url,
+                Some(Box::new(info)),
+            ))
+        }
+        mime::VIDEO => {
+            let mut info = VideoInfo::new();
+            info.mimetype = mimetype;
+            MessageType::Video(VideoMessageEventContent::plain(
+                body,
+                url,
+                Some(Box::new(info)),
+            ))
+        }
+        mime::AUDIO => {
+            let mut info = AudioInfo::new();
+            info.mimetype = mimetype;
+            MessageType::Audio(AudioMessageEventContent::plain(
+                body,
+                url,
+                Some(Box::new(info)),
+            ))
+        }
+        _ => {
+            let mut info = FileInfo::new();
+            info.mimetype = mimetype;
+            MessageType::File(FileMessageEventContent::plain(
+                body,
+                url,
+                Some(Box::new(info)),
+            ))
+        }
+    }
+}
+
+fn remote_url(file: &Path) -> Option<Url> {
+    file.to_str()
+        .and_then(|file| Url::parse(file).ok())
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// Temporary copy of a remote file, removed again on drop
+struct Download {
+    path: PathBuf,
+    _dir: TempDir,
+}
+
+impl Download {
+    async fn fetch(client: &MatrixClient, url: &Url) -> Result<Self> {
+        let name = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|name| !matches!(*name, "" | "." | ".."))
+            .unwrap_or("download");
+        // a fresh directory only we can access, so nothing can be planted in there
+        let dir = tempfile::Builder::new()
+            .prefix(&format!("{}-", crate::APP_NAME))
+            .tempdir()?;
+        let path = dir.path().join(name);
+
+        let mut response = client
+            .http_client()
+            .get(url.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+        let mut writer = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        while let Some(chunk) = response.chunk().await? {
+            writer.write_all(&chunk)?;
+        }
+        Ok(Self { path, _dir: dir })
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// How long --wait-for-delivery syncs before giving up
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default size in bytes of the parts of a split Message
+const SPLIT_SIZE: usize = 30_000;
+
+/// Smallest allowed --split size, so a Room isn't flooded with tiny Messages
+const MIN_SPLIT_SIZE: usize = 1024;
+
+fn parse_split_size(s: &str) -> std::result::Result<usize, String> {
+    let size = s.parse::<usize>().map_err(|e| e.to_string())?;
+    if size < MIN_SPLIT_SIZE {
+        return Err(format!("must be at least {}", MIN_SPLIT_SIZE));
+    }
+    Ok(size)
+}
+
+/// Split `msg` into parts of at most `limit` bytes, at line breaks where possible
+fn split_message(msg: &str, limit: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    for mut line in msg.split_inclusive('\n') {
+        while !line.is_empty() {
+            if chunk.len() + line.len() <= limit {
+                chunk.push_str(line);
+                break;
+            }
+            if !chunk.is_empty() {
+                chunks.push(mem::take(&mut chunk));
+                continue;
+            }
+            // the line alone is too long, cut it at a char boundary
+            let mut end = limit;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            if end == 0 {
+                end = line.chars().next().map_or(line.len(), char::len_utf8);
+            }
+            chunks.push(line[..end].to_owned());
+            line = &line[end..];
+        }
+    }
+    if !chunk.is_empty() || chunks.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Read a Message of at most `max_size` bytes, which has to be valid UTF-8
+fn read_message(reader: impl Read, max_size: u64) -> Result<String> {
+    let mut data = Vec::new();
+    reader.take(max_size + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > max_size {
+        return Error::custom("Message is larger than --max-size");
+    }
+    String::from_utf8(data)
+        .map_err(|_| Error::Custom("Message is not valid UTF-8, use send-file for binary data"))
+}
+
+/// Open $VISUAL/$EDITOR on a temporary file and return what was saved
+fn edit_message() -> Result<String> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| String::from("vi"));
+    let mut args = editor.split_whitespace();
+    let program = args.next().ok_or(Error::Custom("Invalid editor"))?;
+
+    // created exclusively and only readable by us, removed again on drop
+    let file = tempfile::Builder::new()
+        .prefix(&format!("{}-", crate::APP_NAME))
+        .suffix(".md")
+        .tempfile()?;
+    let status = process::Command::new(program)
+        .args(args)
+        .arg(file.path())
+        .status();
+    let msg = fs::read_to_string(file.path());
+    file.close()?;
+
+    if !status?.success() {
+        return Error::custom("Editor exited with an error");
+    }
+    let msg = msg?;
+    if msg.trim().is_empty() {
+        return Error::custom("Aborting due to empty message");
+    }
+    Ok(msg)
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct RedactCommand {
+    /// Room ID or Alias
+    room: OwnedRoomOrAliasId,
+
+    /// Event ID
+    event: OwnedEventId,
+
+    /// Reason for redaction
+    #[clap(long)]
+    reason: Option<String>,
+}
+
+impl RedactCommand {
     async fn run(self, client: MatrixClient) -> Result {
+        client.refuse_dry_run()?;
+        let room = client.joined_room_by_id_or_alias(&self.room).await?;
         client
-            .joined_room(&self.room)?
-            .send_attachment(
-                self.text
-                    .as_ref()
-                    .map(Cow::from)
-                    .or_else(|| self.file.file_name().as_ref().map(|o| o.to_string_lossy()))
-                    .ok_or(Error::InvalidFile)?
-                    .as_ref(),
-                self.mime.as_ref().unwrap_or(
-                    &mime_guess::from_path(&self.file).first_or(mime::APPLICATION_OCTET_STREAM),
+            .rate_limited(|| room.redact(&self.event, self.reason.as_deref(), None))
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct EditCommand {
+    /// Room ID or Alias
+    room: OwnedRoomOrAliasId,
+
+    /// Event ID of the Message to replace
+    event: OwnedEventId,
+
+    /// New Message
+    message: String,
+
+    #[clap(flatten)]
+    format: MessageFormat,
+}
+
+impl EditCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        let new_content = self.format.content(self.message, None);
+        let mut content = new_content.clone();
+        content.relates_to = Some(message::Relation::Replacement(Replacement::new(
+            self.event,
+            Box::new(new_content),
+        )));
+        let room = client.sendable_room_by_id_or_alias(&self.room).await?;
+        if client.dry_run() {
+            let content = serde_json::to_value(&content)?;
+            match output {
+                OutputFormat::Text => println!("{}\t{}", room.room_id(), content),
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({ "room_id": room.room_id(), "content": content })
+                ),
+            }
+            return Ok(());
+        }
+        let event_id = client
+            .rate_limited(|| room.send(content.clone(), None))
+            .await?
+            .event_id;
+        print_event_id(&event_id, output);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct SendRawCommand {
+    /// Room ID or Alias
+    room: OwnedRoomOrAliasId,
+
+    /// Event type, e.g. m.room.message
+    #[clap(long = "type", value_name = "EVENT_TYPE")]
+    event_type: String,
+
+    /// File with the JSON content of the Event, read from stdin if missing or -
+    file: Option<PathBuf>,
+}
+
+impl SendRawCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        let content: serde_json::Value = match self.file.as_deref() {
+            Some(path) if path != Path::new("-") => serde_json::from_reader(BufReader::new(
+                File::open(path).map_err(|e| Error::ReadFile(path.to_owned(), e))?,
+            ))?,
+            _ => serde_json::from_reader(io::stdin().lock())?,
+        };
+        if !content.is_object() {
+            return Error::custom("Event content must be a JSON object");
+        }
+        let room = client.sendable_room_by_id_or_alias(&self.room).await?;
+        if client.dry_run() {
+            match output {
+                OutputFormat::Text => {
+                    println!("{}\t{}\t{}", room.room_id(), self.event_type, content)
+                }
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "room_id": room.room_id(),
+                        "type": self.event_type,
+                        "content": content,
+                    })
                 ),
-                &mut File::open(&self.file)?,
-                AttachmentConfig::new(),
-            )
+            }
+            return Ok(());
+        }
+        let event_id = client
+            .rate_limited(|| room.send_raw(content.clone(), &self.event_type, None))
+            .await
+            .map_err(forbidden)?
+            .event_id;
+        print_event_id(&event_id, output);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct CreateCommand {
+    /// Room Name
+    #[clap(long)]
+    name: Option<String>,
+
+    /// Room Topic
+    #[clap(long)]
+    topic: Option<String>,
+
+    /// Localpart of the Room Alias
+    #[clap(long)]
+    alias: Option<String>,
+
+    /// Users to invite
+    #[clap(long)]
+    invite: Vec<OwnedUserId>,
+
+    /// Create a private Room (default)
+    #[clap(long, conflicts_with = "public")]
+    private: bool,
+
+    /// Create a public Room, listed in the Room directory
+    #[clap(long)]
+    public: bool,
+
+    /// Enable end-to-end encryption
+    #[clap(long)]
+    encrypted: bool,
+}
+
+impl CreateCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        client.refuse_dry_run()?;
+        let mut initial_state = Vec::new();
+        if self.encrypted {
+            initial_state.push(
+                Raw::new(&json!({
+                    "type": "m.room.encryption",
+                    "state_key": "",
+                    "content": RoomEncryptionEventContent::new(
+                        EventEncryptionAlgorithm::MegolmV1AesSha2
+                    ),
+                }))?
+                .cast(),
+            );
+        }
+
+        let mut request = CreateRoomRequest::new();
+        request.name = self.name.as_deref();
+        request.topic = self.topic.as_deref();
+        request.room_alias_name = self.alias.as_deref();
+        request.invite = &self.invite;
+        request.initial_state = &initial_state;
+        if self.public && !self.private {
+            request.visibility = Visibility::Public;
+            request.preset = Some(RoomPreset::PublicChat);
+        } else {
+            request.visibility = Visibility::Private;
+            request.preset = Some(RoomPreset::PrivateChat);
+        }
+
+        let (client, request) = (&client, &request);
+        let room_id = client
+            .rate_limited(move || async move {
+                client
+                    .create_room(request.clone())
+                    .await
+                    .map_err(matrix_sdk::Error::from)
+            })
+            .await?
+            .room_id;
+        match output {
+            OutputFormat::Text => println!("{}", room_id),
+            OutputFormat::Json => println!("{}", json!({ "room_id": room_id })),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct InfoCommand {
+    /// Room ID or Alias
+    room: OwnedRoomOrAliasId,
+}
+
+impl InfoCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        let room = client.joined_room_by_id_or_alias(&self.room).await?;
+        let name = room.display_name().await.ok();
+        let topic = room.topic();
+        let alias = room.canonical_alias();
+        let members = room.joined_members_count();
+        let encrypted = room.is_encrypted();
+        let join_rule = room.join_rule();
+        match output {
+            OutputFormat::Text => {
+                println!("Room ID\t{}", room.room_id());
+                if let Some(name) = name {
+                    println!("Name\t{}", name);
+                }
+                if let Some(topic) = topic {
+                    println!("Topic\t{}", topic);
+                }
+                if let Some(alias) = alias {
+                    println!("Alias\t{}", alias);
+                }
+                println!("Members\t{}", members);
+                println!("Encrypted\t{}", encrypted);
+                println!("Join Rule\t{}", join_rule.as_str());
+            }
+            OutputFormat::Json => println!(
+                "{}",
+                json!({
+                    "room_id": room.room_id(),
+                    "name": name,
+                    "topic": topic,
+                    "canonical_alias": alias,
+                    "joined_members": members,
+                    "encrypted": encrypted,
+                    "join_rule": join_rule.as_str(),
+                })
+            ),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct ReadCommand {
+    /// Room ID or Alias
+    room: OwnedRoomOrAliasId,
+
+    /// Number of Messages to read
+    #[clap(long, default_value_t = 20)]
+    limit: usize,
+
+    /// Only read Messages sent at or after this time (RFC 3339)
+    #[clap(long)]
+    since: Option<humantime::Timestamp>,
+
+    /// Only read Messages sent at or before this time (RFC 3339)
+    #[clap(long)]
+    until: Option<humantime::Timestamp>,
+}
+
+impl ReadCommand {
+    async fn run(self, client: MatrixClient, output: OutputFormat) -> Result {
+        let since = self
+            .since
+            .and_then(|ts| MilliSecondsSinceUnixEpoch::from_system_time(*ts));
+        let until = self
+            .until
+            .and_then(|ts| MilliSecondsSinceUnixEpoch::from_system_time(*ts));
+        let room = client.joined_room_by_id_or_alias(&self.room).await?;
+        let mut messages = Vec::new();
+        let mut from = client.sync_token().await;
+        while let Some(token) = from.take() {
+            let mut options = MessagesOptions::backward(&token);
+            options.limit = UInt::try_from(self.limit - messages.len()).unwrap_or(UInt::MAX);
+            let response = room.messages(options).await?;
+            let mut crossed_since = false;
+            for event in response.chunk {
+                if let Ok(AnyRoomEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+                    MessageLikeEvent::Original(event),
+                ))) = event.event.deserialize()
+                {
+                    if since.is_some_and(|since| event.origin_server_ts < since) {
+                        crossed_since = true;
+                        break;
+                    }
+                    if until.is_none_or(|until| event.origin_server_ts <= until) {
+                        messages.push(event);
+                    }
+                }
+            }
+            if messages.len() < self.limit && !crossed_since {
+                from = response.end;
+            }
+        }
+        messages.truncate(self.limit);
+        for message in messages.into_iter().rev() {
+            print_message(
+                room.room_id(),
+                &message.sender,
+                message.origin_server_ts,
+                &message.content,
+                output,
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct MarkReadCommand {
+    /// Room ID or Alias
+    room: OwnedRoomOrAliasId,
+
+    /// Event ID to mark as read (Defaults to the latest event)
+    #[clap(long)]
+    event: Option<OwnedEventId>,
+}
+
+impl MarkReadCommand {
+    async fn run(self, client: MatrixClient) -> Result {
+        client.refuse_dry_run()?;
+        let room = client.joined_room_by_id_or_alias(&self.room).await?;
+        let event_id = match self.event {
+            Some(event_id) => event_id,
+            None => {
+                let token = client
+                    .sync_token()
+                    .await
+                    .ok_or(Error::Custom("Missing sync token"))?;
+                let mut options = MessagesOptions::backward(&token);
+                options.limit = UInt::from(1_u32);
+                let response = room.messages(options).await?;
+                response
+                    .chunk
+                    .first()
+                    .and_then(|event| event.event.get_field("event_id").ok().flatten())
+                    .ok_or(Error::Custom("Room has no events"))?
+            }
+        };
+        client
+            .rate_limited(|| room.read_marker(&event_id, Some(&event_id)))
             .await?;
         Ok(())
     }
 }
+
+fn print_event_id(event_id: &EventId, output: OutputFormat) {
+    match output {
+        OutputFormat::Text => println!("{}", event_id),
+        OutputFormat::Json => println!("{}", json!({ "event_id": event_id })),
+    }
+}
+
+/// Run `request` again on server errors, at most `max_retries` times
+///
+/// Rate limiting is waited out by [`MatrixClient::rate_limited`], unless that was turned off with
+/// `--respect-rate-limits=false`, then it counts as a retry here.
+async fn with_retries<T, F, Fut>(
+    client: &MatrixClient,
+    max_retries: u32,
+    mut request: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = matrix_sdk::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match client.rate_limited(&mut request).await {
+            Ok(response) => return Ok(response),
+            Err(e) if attempt < max_retries => match retry_delay(client, &e, attempt) {
+                Some(delay) => {
+                    time::sleep(delay).await;
+                    attempt += 1;
+                }
+                None => return Err(e.into()),
+            },
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Delay before retrying after `e`, None if retrying won't help
+fn retry_delay(client: &MatrixClient, e: &matrix_sdk::Error, attempt: u32) -> Option<Duration> {
+    let backoff = Duration::from_secs(1 << attempt.min(6));
+    match e {
+        matrix_sdk::Error::Http(HttpError::Server(status)) if status.is_server_error() => {
+            Some(backoff)
+        }
+        matrix_sdk::Error::Http(http) if !client.respect_rate_limits() => {
+            match http.client_api_error_kind() {
+                Some(ErrorKind::LimitExceeded { retry_after_ms }) => {
+                    Some(retry_after_ms.unwrap_or(backoff))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Atomically replace `path` with the event ids by writing a sibling file and renaming it
+fn write_event_ids(path: &Path, event_ids: &[OwnedEventId]) -> Result {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let contents: Vec<&str> = event_ids.iter().map(|e| e.as_str()).collect();
+    fs::write(&tmp, contents.join("\n"))?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct SetNameCommand {
+    /// Room ID or Alias
+    room: OwnedRoomOrAliasId,
+
+    /// New Name
+    name: OwnedRoomName,
+}
+
+impl SetNameCommand {
+    async fn run(self, client: MatrixClient) -> Result {
+        client.refuse_dry_run()?;
+        let room = client.joined_room_by_id_or_alias(&self.room).await?;
+        let content = RoomNameEventContent::new(Some(self.name));
+        client
+            .rate_limited(|| room.send_state_event(content.clone(), ""))
+            .await
+            .map_err(forbidden)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct SetTopicCommand {
+    /// Room ID or Alias
+    room: OwnedRoomOrAliasId,
+
+    /// New Topic
+    topic: String,
+}
+
+impl SetTopicCommand {
+    async fn run(self, client: MatrixClient) -> Result {
+        client.refuse_dry_run()?;
+        let room = client.joined_room_by_id_or_alias(&self.room).await?;
+        let content = RoomTopicEventContent::new(self.topic);
+        client
+            .rate_limited(|| room.send_state_event(content.clone(), ""))
+            .await
+            .map_err(forbidden)?;
+        Ok(())
+    }
+}
+
+/// Turn a 403 on state changes into a hint about the missing power level
+fn forbidden(e: matrix_sdk::Error) -> Error {
+    match &e {
+        matrix_sdk::Error::Http(http)
+            if matches!(http.client_api_error_kind(), Some(ErrorKind::Forbidden)) =>
+        {
+            Error::Custom("Insufficient power level to change this room")
+        }
+        _ => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_fence_is_longer_than_embedded_fences() {
+        assert_eq!(code_fence("no backticks"), "```");
+        assert_eq!(code_fence("```rust\nfn main() {}\n```"), "````");
+        assert_eq!(code_fence("````\n```\n````"), "`````");
+    }
+
+    #[test]
+    fn code_with_embedded_fences_renders_as_one_block() {
+        let format = MessageFormat::try_parse_from(["send", "--code"]).unwrap();
+        for msg in ["```\ninner\n```", "````\n```\ninner\n```\n````"] {
+            let html = match format.content(msg.to_owned(), None).msgtype {
+                MessageType::Text(text) => text.formatted.unwrap().body,
+                msgtype => panic!("unexpected msgtype {:?}", msgtype),
+            };
+            assert_eq!(html.matches("<pre>").count(), 1, "{}", html);
+            assert!(html.contains(msg), "{}", html);
+        }
+    }
+
+    #[test]
+    fn split_message_keeps_short_messages() {
+        assert_eq!(split_message("hello", 10), ["hello"]);
+        assert_eq!(split_message("", 10), [""]);
+    }
+
+    #[test]
+    fn split_message_prefers_line_breaks() {
+        assert_eq!(split_message("aaa\nbbb\nc", 5), ["aaa\n", "bbb\nc"]);
+    }
+
+    #[test]
+    fn split_message_cuts_long_lines_at_char_boundaries() {
+        let msg = "éééé";
+        let chunks = split_message(msg, 3);
+        assert_eq!(chunks, ["é", "é", "é", "é"]);
+        assert_eq!(chunks.concat(), msg);
+    }
+
+    #[test]
+    fn split_size_has_a_minimum() {
+        assert!(parse_split_size("0").is_err());
+        assert!(parse_split_size("1023").is_err());
+        assert_eq!(parse_split_size("1024"), Ok(1024));
+    }
+
+    #[test]
+    fn join_format_requires_and_send() {
+        let args = ["join", "#room:example.org", "--notice", "--and-send", "hi"];
+        assert!(JoinCommand::try_parse_from(&args[..3]).is_err());
+        assert!(JoinCommand::try_parse_from(args).is_ok());
+    }
+
+    #[test]
+    fn split_conflicts_with_html() {
+        let args = [
+            "send",
+            "!room:example.org",
+            "msg",
+            "--split",
+            "--html",
+            "<b>msg</b>",
+        ];
+        assert!(SendCommand::try_parse_from(args).is_err());
+        assert!(SendCommand::try_parse_from(&args[..4]).is_ok());
+    }
+}
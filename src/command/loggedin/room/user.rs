@@ -1,10 +1,27 @@
-use crate::{matrix::MatrixClient, Result};
+use super::forbidden;
+
+use crate::{
+    matrix::MatrixClient,
+    output::{self, OutputFormat},
+    Error, Result,
+};
 
 use std::cmp::Reverse;
 
-use clap::Parser;
+use clap::{ArgEnum, Parser};
+
+use serde_json::json;
 
-use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
+use matrix_sdk::{
+    room,
+    ruma::{
+        events::{
+            room::{member::MembershipState, power_levels::RoomPowerLevelsEventContent},
+            RoomEventType, SyncStateEvent,
+        },
+        Int, OwnedRoomId, OwnedUserId,
+    },
+};
 
 #[derive(Debug, Parser)]
 pub(crate) enum Command {
@@ -14,20 +31,33 @@ pub(crate) enum Command {
     /// Ban a user
     Ban(BanCommand),
 
+    /// Unban a user
+    Unban(UnbanCommand),
+
     /// List users
     List(ListCommand),
 
     /// Invite user
     Invite(InviteCommand),
+
+    /// Set the power level of a user
+    PowerLevel(PowerLevelCommand),
 }
 
 impl Command {
-    pub(super) async fn run(self, client: MatrixClient, room: OwnedRoomId) -> Result {
+    pub(super) async fn run(
+        self,
+        client: MatrixClient,
+        room: OwnedRoomId,
+        output: OutputFormat,
+    ) -> Result {
         match self {
             Self::Kick(command) => command.run(client, room).await,
             Self::Ban(command) => command.run(client, room).await,
-            Self::List(command) => command.run(client, room).await,
-            Self::Invite(command) => command.run(client, room).await,
+            Self::Unban(command) => command.run(client, room).await,
+            Self::List(command) => command.run(client, room, output).await,
+            Self::Invite(command) => command.run(client, room, output).await,
+            Self::PowerLevel(command) => command.run(client, room).await,
         }
     }
 }
@@ -43,9 +73,12 @@ pub(crate) struct KickCommand {
 
 impl KickCommand {
     async fn run(self, client: MatrixClient, room: OwnedRoomId) -> Result {
+        client.refuse_dry_run()?;
+        let room = client.joined_room(&room)?;
+        let power_levels = power_levels(&room).await?;
+        check_power_level(&client, &power_levels, power_levels.kick).await?;
         client
-            .joined_room(&room)?
-            .kick_user(&self.user, self.reason.as_deref())
+            .rate_limited(|| room.kick_user(&self.user, self.reason.as_deref()))
             .await?;
         Ok(())
     }
@@ -62,28 +95,101 @@ pub(crate) struct BanCommand {
 
 impl BanCommand {
     async fn run(self, client: MatrixClient, room: OwnedRoomId) -> Result {
+        client.refuse_dry_run()?;
+        let room = client.joined_room(&room)?;
+        let power_levels = power_levels(&room).await?;
+        check_power_level(&client, &power_levels, power_levels.ban).await?;
         client
-            .joined_room(&room)?
-            .ban_user(&self.user, self.reason.as_deref())
+            .rate_limited(|| room.ban_user(&self.user, self.reason.as_deref()))
             .await?;
         Ok(())
     }
 }
 
 #[derive(Debug, Parser)]
-pub(crate) struct ListCommand {}
+pub(crate) struct UnbanCommand {
+    /// User ID
+    user: OwnedUserId,
+}
 
-impl ListCommand {
+impl UnbanCommand {
     async fn run(self, client: MatrixClient, room: OwnedRoomId) -> Result {
-        let mut members = client.joined_room(&room)?.joined_members().await?;
+        client.refuse_dry_run()?;
+        let room = client.joined_room(&room)?;
+        let power_levels = power_levels(&room).await?;
+        check_power_level(&client, &power_levels, power_levels.ban).await?;
+        client
+            .rate_limited(|| room.unban_user(&self.user, None))
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct ListCommand {
+    /// Only list users with at least this power level
+    #[clap(long, allow_hyphen_values = true)]
+    min_power: Option<i64>,
+
+    /// Only list users with this membership
+    #[clap(long, arg_enum)]
+    membership: Option<Membership>,
+}
+
+#[derive(Clone, ArgEnum, Debug)]
+enum Membership {
+    Joined,
+    Invited,
+    Banned,
+    Left,
+}
+
+impl Membership {
+    fn state(&self) -> MembershipState {
+        match self {
+            Self::Joined => MembershipState::Join,
+            Self::Invited => MembershipState::Invite,
+            Self::Banned => MembershipState::Ban,
+            Self::Left => MembershipState::Leave,
+        }
+    }
+}
+
+impl ListCommand {
+    async fn run(self, client: MatrixClient, room: OwnedRoomId, output: OutputFormat) -> Result {
+        let room = client.joined_room(&room)?;
+        let mut members = match &self.membership {
+            Some(membership) => {
+                let state = membership.state();
+                let mut members = room.members().await?;
+                members.retain(|m| *m.membership() == state);
+                members
+            }
+            None => room.joined_members().await?,
+        };
+        if let Some(min_power) = self.min_power {
+            members.retain(|m| m.power_level() >= min_power);
+        }
 
         members.sort_by_key(|m| Reverse(m.power_level()));
 
         for member in members {
-            if let Some(name) = member.display_name() {
-                println!("{}\t{}\t{}", member.user_id(), member.power_level(), name);
-            } else {
-                println!("{}\t{}", member.user_id(), member.power_level());
+            match output {
+                OutputFormat::Text => {
+                    if let Some(name) = member.display_name() {
+                        println!("{}\t{}\t{}", member.user_id(), member.power_level(), name);
+                    } else {
+                        println!("{}\t{}", member.user_id(), member.power_level());
+                    }
+                }
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "user_id": member.user_id(),
+                        "power_level": member.power_level(),
+                        "display_name": member.display_name(),
+                    })
+                ),
             }
         }
         Ok(())
@@ -92,16 +198,127 @@ impl ListCommand {
 
 #[derive(Debug, Parser)]
 pub(crate) struct InviteCommand {
+    /// User IDs
+    #[clap(required = true)]
+    users: Vec<OwnedUserId>,
+}
+
+impl InviteCommand {
+    async fn run(self, client: MatrixClient, room: OwnedRoomId, output: OutputFormat) -> Result {
+        client.refuse_dry_run()?;
+        let room = client.joined_room(&room)?;
+        let mut failed = 0;
+        let room = &room;
+        for user in &self.users {
+            let result = client
+                .rate_limited(move || room.invite_user_by_id(user))
+                .await;
+            if result.is_err() {
+                failed += 1;
+            }
+            match output {
+                OutputFormat::Text => match &result {
+                    Ok(()) => println!("{}\tinvited", user),
+                    Err(e) => println!("{}\tfailed\t{}", user, e),
+                },
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "user_id": user,
+                        "invited": result.is_ok(),
+                        "error": result.err().map(|e| e.to_string()),
+                    })
+                ),
+            }
+        }
+        if output == OutputFormat::Text && !output::quiet() {
+            println!("{} invited, {} failed", self.users.len() - failed, failed);
+        }
+        if failed > 0 {
+            return Error::custom("Some invites failed");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct PowerLevelCommand {
     /// User ID
     user: OwnedUserId,
+
+    /// New power level
+    #[clap(long, allow_hyphen_values = true)]
+    level: i64,
 }
 
-impl InviteCommand {
+impl PowerLevelCommand {
     async fn run(self, client: MatrixClient, room: OwnedRoomId) -> Result {
+        client.refuse_dry_run()?;
+        let room = client.joined_room(&room)?;
+        let mut content = power_levels(&room).await?;
+        let required = content
+            .events
+            .get(&RoomEventType::RoomPowerLevels)
+            .copied()
+            .unwrap_or(content.state_default);
+        let own_level = check_power_level(&client, &content, required).await?;
+        if self.level > own_level {
+            return Error::custom("Can't set a power level above your own");
+        }
+        let own_user_id = client.user_id().await.ok_or(Error::NotLoggedIn)?;
+        let target_level = i64::from(
+            content
+                .users
+                .get(&self.user)
+                .copied()
+                .unwrap_or(content.users_default),
+        );
+        if self.user != own_user_id && target_level >= own_level {
+            return Error::custom("Can't change the power level of a user at or above your own");
+        }
+        let level = Int::new(self.level).ok_or(Error::Custom("Invalid power level"))?;
+
+        content.users.insert(self.user, level);
         client
-            .joined_room(&room)?
-            .invite_user_by_id(&self.user)
-            .await?;
+            .rate_limited(|| room.send_state_event(content.clone(), ""))
+            .await
+            .map_err(forbidden)?;
         Ok(())
     }
 }
+
+/// The current power levels of `room`, or the defaults if there are none
+async fn power_levels(room: &room::Joined) -> Result<RoomPowerLevelsEventContent> {
+    Ok(
+        match room
+            .get_state_event_static::<RoomPowerLevelsEventContent>("")
+            .await?
+            .map(|event| event.deserialize())
+            .transpose()?
+        {
+            Some(SyncStateEvent::Original(event)) => event.content,
+            _ => RoomPowerLevelsEventContent::new(),
+        },
+    )
+}
+
+/// Fail unless our own power level is at least `required`, returns our own level
+async fn check_power_level(
+    client: &MatrixClient,
+    power_levels: &RoomPowerLevelsEventContent,
+    required: Int,
+) -> Result<i64> {
+    let own_user_id = client.user_id().await.ok_or(Error::NotLoggedIn)?;
+    let own_level = i64::from(
+        power_levels
+            .users
+            .get(&own_user_id)
+            .copied()
+            .unwrap_or(power_levels.users_default),
+    );
+    let required = i64::from(required);
+    if own_level < required {
+        return Err(Error::InsufficientPowerLevel(own_level, required));
+    }
+    Ok(own_level)
+}
@@ -0,0 +1,43 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::{output::OutputFormat, Result};
+
+use matrix_sdk::ruma::OwnedRoomOrAliasId;
+
+use serde::Deserialize;
+
+use url::Url;
+
+/// Defaults read from config.json in the data directory, overridden by command line flags
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Homeserver to login to
+    #[serde(default)]
+    pub(crate) homeserver: Option<String>,
+
+    /// HTTP(S) proxy to connect through
+    #[serde(default)]
+    pub(crate) proxy: Option<Url>,
+
+    /// Output format
+    #[serde(default)]
+    pub(crate) output: Option<OutputFormat>,
+
+    /// Room to send to when none is given
+    #[serde(default)]
+    pub(crate) default_room: Option<OwnedRoomOrAliasId>,
+}
+
+impl Config {
+    /// Load the config, a missing file is treated as empty
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        match File::open(path) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{Error, Result};
+
+use argon2::Argon2;
+
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+const MAGIC: &[u8] = b"matrix-send encrypted v1\n";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Whether `data` was produced by [`encrypt`]
+pub(crate) fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Encrypt `plaintext` with a key derived from `passphrase`
+///
+/// The output is laid out as `MAGIC | salt | nonce | ciphertext`.
+pub(crate) fn encrypt(passphrase: &str, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut salt = [0; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let ciphertext = cipher(passphrase, &salt)?
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| Error::Custom("Failed to encrypt session"))?;
+
+    let mut data = Vec::with_capacity(MAGIC.len() + SALT_LEN + NONCE_LEN + ciphertext.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&nonce);
+    data.extend(ciphertext);
+    Ok(data)
+}
+
+/// Decrypt data produced by [`encrypt`]
+pub(crate) fn decrypt(passphrase: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let data = data
+        .strip_prefix(MAGIC)
+        .filter(|data| data.len() >= SALT_LEN + NONCE_LEN)
+        .ok_or(Error::Custom("Corrupt encrypted session"))?;
+    let (salt, data) = data.split_at(SALT_LEN);
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+    cipher(passphrase, salt)?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| Error::Custom("Wrong session passphrase"))
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<ChaCha20Poly1305> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| Error::Custom("Failed to derive session key"))?;
+    Ok(ChaCha20Poly1305::new(&key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = encrypt("passphrase", b"session").unwrap();
+        assert!(is_encrypted(&data));
+        assert_eq!(decrypt("passphrase", &data).unwrap(), b"session");
+    }
+
+    #[test]
+    fn wrong_passphrase_fails() {
+        let data = encrypt("passphrase", b"session").unwrap();
+        assert!(decrypt("wrong", &data).is_err());
+    }
+
+    #[test]
+    fn corrupt_data_fails() {
+        assert!(!is_encrypted(b"{}"));
+        assert!(decrypt("passphrase", MAGIC).is_err());
+    }
+}
@@ -6,19 +6,34 @@ use crate::{Error, Result};
 use directories::ProjectDirs;
 
 const SESSION_FILE: &str = "session.json";
+const CONFIG_FILE: &str = "config.json";
+const PROFILES_DIR: &str = "profiles";
 
-pub(crate) struct Directories {
+pub struct Directories {
     pub(crate) session_file: PathBuf,
+    pub(crate) config_file: PathBuf,
 }
 
 impl Directories {
-    pub(crate) fn new() -> Result<Self> {
-        let dirs =
-            ProjectDirs::from_path(PathBuf::from(crate::APP_NAME)).ok_or(Error::NoNomeDirectory)?;
+    pub fn new(data_dir: Option<PathBuf>, profile: Option<&str>) -> Result<Self> {
+        let mut data_dir = match data_dir {
+            Some(data_dir) => data_dir,
+            None => ProjectDirs::from_path(PathBuf::from(crate::APP_NAME))
+                .ok_or(Error::NoNomeDirectory)?
+                .data_dir()
+                .to_owned(),
+        };
+        if let Some(profile) = profile {
+            if profile.is_empty() || profile.starts_with('.') || profile.contains(['/', '\\']) {
+                return Error::custom("Invalid profile name");
+            }
+            data_dir = data_dir.join(PROFILES_DIR).join(profile);
+        }
 
-        fs::create_dir_all(dirs.data_dir())?;
+        fs::create_dir_all(&data_dir)?;
         Ok(Directories {
-            session_file: dirs.data_dir().join(SESSION_FILE),
+            session_file: data_dir.join(SESSION_FILE),
+            config_file: data_dir.join(CONFIG_FILE),
         })
     }
 }
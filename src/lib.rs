@@ -0,0 +1,290 @@
+use crate::config::Config;
+pub use crate::dir::Directories;
+pub use crate::matrix::{ClientOptions, Homeserver, MatrixClient};
+use crate::output::OutputFormat;
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use clap::{ArgAction, Parser};
+
+use matrix_sdk::{
+    attachment::AttachmentConfig,
+    ruma::{events::room::message::RoomMessageEventContent, OwnedEventId, RoomOrAliasId},
+};
+
+use thiserror::Error;
+
+use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, EnvFilter, Layer};
+
+mod command;
+mod config;
+mod crypto;
+mod dir;
+mod matrix;
+mod output;
+mod progress;
+mod ratelimit;
+mod thumbnail;
+
+const APP_NAME: &str = env!("CARGO_PKG_NAME");
+
+#[derive(Debug, Parser)]
+struct Opt {
+    #[clap(flatten)]
+    options: ClientOptions,
+
+    /// Directory to store the session in
+    #[clap(long, global = true, env = "MATRIX_SEND_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Named profile to use, keeping a separate session per profile
+    #[clap(long, global = true)]
+    profile: Option<String>,
+
+    /// Output format (Defaults to output in config.json, otherwise text)
+    #[clap(long, global = true, arg_enum, value_name = "FORMAT")]
+    output: Option<OutputFormat>,
+
+    /// Only print errors and requested output
+    #[clap(short, long, global = true)]
+    quiet: bool,
+
+    /// Log more (-v info, -vv debug, -vvv trace), RUST_LOG takes precedence
+    #[clap(short, long, global = true, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Additionally write JSON formatted logs to this file
+    #[clap(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: command::Command,
+}
+
+/// Errors, mapped to exit codes by [`Error::exit_code`]:
+///
+/// * 1: any other error
+/// * 2: invalid command line arguments (reported by clap)
+/// * 3: `NotLoggedIn`, `SessionExpired`
+/// * 4: `InvalidRoom`
+/// * 5: `InvalidFile`
+/// * 6: network errors (`Http`, `Download`, `SyncFailed`, `Unreachable`)
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Custom(&'static str),
+
+    #[error("No valid home directory path")]
+    NoNomeDirectory,
+
+    #[error("Not logged in")]
+    NotLoggedIn,
+
+    #[error("Session expired or logged out, run `{} login` again", APP_NAME)]
+    SessionExpired,
+
+    #[error("Invalid username or password")]
+    InvalidCredentials,
+
+    #[error("Invalid Room")]
+    InvalidRoom,
+
+    #[error("Invalid File")]
+    InvalidFile,
+
+    #[error("Insufficient power level (have {0}, need {1})")]
+    InsufficientPowerLevel(i64, i64),
+
+    #[error("Room is encrypted, but this build can't encrypt messages")]
+    EncryptionNotReady,
+
+    #[error("Environment variable {0} is not set")]
+    MissingEnvVar(String),
+
+    #[error("Invalid client certificate or key")]
+    InvalidClientCertificate,
+
+    #[error("Invalid proxy URL")]
+    InvalidProxy,
+
+    #[error("Failed to read {}: {1}", .0.display())]
+    ReadFile(PathBuf, std::io::Error),
+
+    #[error("Couldn't reach homeserver {0}: {1}")]
+    Unreachable(String, Box<Error>),
+
+    #[error("Sync failed after {0} attempt(s): {1}")]
+    SyncFailed(u32, matrix_sdk::Error),
+
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Matrix(#[from] matrix_sdk::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Http(#[from] matrix_sdk::HttpError),
+
+    #[error(transparent)]
+    ClientBuild(#[from] matrix_sdk::ClientBuildError),
+
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+
+    #[error("Download failed: {0}")]
+    Download(#[from] reqwest::Error),
+}
+
+impl Error {
+    pub(crate) fn custom<T>(message: &'static str) -> Result<T> {
+        Err(Error::Custom(message))
+    }
+
+    /// Turn errors about the homeserver rejecting our access token into `SessionExpired`
+    pub(crate) fn or_session_expired(self) -> Self {
+        let expired = match &self {
+            Error::SyncFailed(_, matrix_sdk::Error::Http(e))
+            | Error::Matrix(matrix_sdk::Error::Http(e))
+            | Error::Http(e) => matrix::is_unknown_token(e),
+            _ => false,
+        };
+        if expired {
+            Error::SessionExpired
+        } else {
+            self
+        }
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::NotLoggedIn | Error::SessionExpired => 3,
+            Error::InvalidRoom => 4,
+            Error::InvalidFile => 5,
+            Error::Http(_)
+            | Error::Download(_)
+            | Error::SyncFailed(..)
+            | Error::Unreachable(..)
+            | Error::Matrix(matrix_sdk::Error::Http(_)) => 6,
+            _ => 1,
+        }
+    }
+}
+
+pub type Result<T = ()> = std::result::Result<T, Error>;
+
+/// Send a Message, rendered as Markdown if `markdown` is set
+pub async fn send_message(
+    client: &MatrixClient,
+    room: &RoomOrAliasId,
+    message: &str,
+    markdown: bool,
+) -> Result<OwnedEventId> {
+    let content = if markdown {
+        RoomMessageEventContent::text_markdown(message)
+    } else {
+        RoomMessageEventContent::text_plain(message)
+    };
+    let room = client.sendable_room_by_id_or_alias(room).await?;
+    Ok(client
+        .rate_limited(|| room.send(content.clone(), None))
+        .await?
+        .event_id)
+}
+
+/// Upload a file and send it, guessing the mime type from its name
+pub async fn send_file(
+    client: &MatrixClient,
+    room: &RoomOrAliasId,
+    path: &Path,
+) -> Result<OwnedEventId> {
+    let body = path
+        .file_name()
+        .ok_or(Error::InvalidFile)?
+        .to_string_lossy();
+    let mime = mime_guess::from_path(path).first_or(mime::APPLICATION_OCTET_STREAM);
+    let room = client.sendable_room_by_id_or_alias(room).await?;
+    let (room, body, mime) = (&room, &body, &mime);
+    // the file is opened again for every attempt
+    Ok(client
+        .rate_limited(move || async move {
+            let mut reader = File::open(path)?;
+            room.send_attachment(body, mime, &mut reader, AttachmentConfig::new())
+                .await
+        })
+        .await?
+        .event_id)
+}
+
+/// Login with a Password and save the session in `dirs`
+pub async fn login(
+    dirs: &Directories,
+    options: &ClientOptions,
+    homeserver: &Homeserver,
+    username: &str,
+    password: &str,
+) -> Result<MatrixClient> {
+    MatrixClient::login(dirs, options, homeserver, username, password, None).await
+}
+
+/// Run the command line interface
+pub async fn run() -> Result {
+    let Opt {
+        mut options,
+        data_dir,
+        profile,
+        output,
+        quiet,
+        verbose,
+        log_file,
+        command,
+    } = Opt::parse();
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::WARN,
+        (false, 1) => LevelFilter::INFO,
+        (false, 2) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    init_logging(level, log_file.as_deref())?;
+    output::set_quiet(quiet);
+
+    let dirs = Directories::new(data_dir, profile.as_deref())?;
+    let config = Config::load(&dirs.config_file)?;
+
+    let output = output.or(config.output).unwrap_or(OutputFormat::Text);
+    if options.proxy.is_none() {
+        options.proxy = config.proxy.clone();
+    }
+
+    let client = MatrixClient::load(&dirs, &options).await;
+
+    command.run(client, &dirs, &config, &options, output).await
+}
+
+/// Log to stderr and to `log_file` if given, at `level` or as filtered by RUST_LOG
+fn init_logging(level: LevelFilter, log_file: Option<&Path>) -> Result {
+    let filter = || {
+        EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| EnvFilter::default().add_directive(level.into()))
+    };
+    let file_layer = log_file
+        .map(|path| -> Result<_> {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Ok(fmt::layer()
+                .json()
+                .with_writer(Mutex::new(file))
+                .with_filter(filter()))
+        })
+        .transpose()?;
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_writer(io::stderr).with_filter(filter()))
+        .with(file_layer)
+        .init();
+    Ok(())
+}
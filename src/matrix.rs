@@ -1,8 +1,16 @@
+use std::env;
+use std::fmt;
 use std::fs;
 use std::fs::File;
+use std::future::Future;
+use std::io::{Read, Write};
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::crypto;
 use crate::dir::Directories;
 use crate::{Error, Result};
 
@@ -10,16 +18,29 @@ use matrix_sdk::{
     config::SyncSettings,
     room,
     ruma::{
-        api::client::session::login::v3::Response as LoginResponse, OwnedDeviceId, OwnedUserId,
-        RoomId,
+        api::client::{
+            discovery::get_supported_versions,
+            error::ErrorKind,
+            filter::{FilterDefinition, LazyLoadOptions},
+            session::login::v3::Response as LoginResponse,
+            sync::sync_events::v3::Filter,
+        },
+        OwnedDeviceId, OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, RoomOrAliasId, UInt,
+        UserId,
     },
-    Client, Session,
+    Client, HttpError, Session,
 };
+use tokio::time;
 use url::Url;
 
+use clap::{ArgAction, Parser};
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+const JOIN_SYNC_ATTEMPTS: usize = 5;
+const RATE_LIMIT_ATTEMPTS: usize = 10;
+
+#[derive(Clone, Serialize, Deserialize)]
 struct SessionData {
     homeserver: Url,
     access_token: String,
@@ -28,19 +49,31 @@ struct SessionData {
 }
 
 impl SessionData {
-    fn load(path: &Path) -> Result<SessionData> {
-        let reader = File::open(path)?;
+    fn load(path: &Path, passphrase: Option<&str>) -> Result<SessionData> {
+        let mut reader = File::open(path)?;
         // matrix-send-rs used to create session.js as world-readable, so just ensuring the correct
         // permissions during writing isn't good enough. We also need to fix the existing files.
         SessionData::set_permissions(&reader)?;
-        Ok(serde_json::from_reader(reader)?)
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        if crypto::is_encrypted(&data) {
+            let passphrase = passphrase.ok_or(Error::Custom(
+                "Session is encrypted, use --session-passphrase-env",
+            ))?;
+            data = crypto::decrypt(passphrase, &data)?;
+        }
+        Ok(serde_json::from_slice(&data)?)
     }
 
-    fn save(&self, path: &Path) -> Result {
+    fn save(&self, path: &Path, passphrase: Option<&str>) -> Result {
         fs::create_dir_all(path.parent().ok_or(Error::NoNomeDirectory)?)?;
-        let writer = File::create(path)?;
-        serde_json::to_writer_pretty(&writer, self)?;
+        let mut data = serde_json::to_vec_pretty(self)?;
+        if let Some(passphrase) = passphrase {
+            data = crypto::encrypt(passphrase, &data)?;
+        }
+        let mut writer = File::create(path)?;
         SessionData::set_permissions(&writer)?;
+        writer.write_all(&data)?;
         Ok(())
     }
 
@@ -48,12 +81,9 @@ impl SessionData {
     fn set_permissions(file: &File) -> Result {
         use std::os::unix::fs::PermissionsExt;
 
-        let perms = file.metadata()?.permissions();
-
         // is the file world-readable? if so, reset the permissions to 600
-        if perms.mode() & 0o4 == 0o4 {
-            file.set_permissions(fs::Permissions::from_mode(0o600))
-                .unwrap();
+        if is_world_readable(file)? {
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
         }
         Ok(())
     }
@@ -79,6 +109,26 @@ impl SessionData {
     }
 }
 
+/// The homeserver rejected our access token, e.g. after a logout elsewhere
+pub(crate) fn is_unknown_token(e: &HttpError) -> bool {
+    matches!(
+        e.client_api_error_kind(),
+        Some(ErrorKind::UnknownToken { .. })
+    )
+}
+
+#[cfg(unix)]
+pub(crate) fn is_world_readable(file: &File) -> Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Ok(file.metadata()?.permissions().mode() & 0o4 == 0o4)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_world_readable(file: &File) -> Result<bool> {
+    Ok(false)
+}
+
 impl From<SessionData> for Session {
     fn from(session: SessionData) -> Self {
         Self {
@@ -89,9 +139,158 @@ impl From<SessionData> for Session {
     }
 }
 
-pub(crate) struct MatrixClient {
+/// Homeserver given as URL, or as server name / User ID to discover via .well-known
+#[derive(Clone, Debug)]
+pub enum Homeserver {
+    Url(Url),
+    ServerName(OwnedServerName),
+    UserId(OwnedUserId),
+}
+
+impl Homeserver {
+    pub fn user_id(&self) -> Option<&UserId> {
+        match self {
+            Self::UserId(user_id) => Some(user_id),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Homeserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Url(url) => url.fmt(f),
+            Self::ServerName(server_name) => server_name.fmt(f),
+            Self::UserId(user_id) => user_id.fmt(f),
+        }
+    }
+}
+
+impl FromStr for Homeserver {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            Url::parse(s)
+                .map(Self::Url)
+                .map_err(|_| "Invalid homeserver URL")
+        } else if s.starts_with('@') {
+            UserId::parse(s)
+                .map(Self::UserId)
+                .map_err(|_| "Invalid User ID")
+        } else {
+            OwnedServerName::try_from(s)
+                .map(Self::ServerName)
+                .map_err(|_| "Invalid server name")
+        }
+    }
+}
+
+#[derive(Debug, Default, Parser)]
+pub struct ClientOptions {
+    /// Client certificate (PEM) for mutual TLS
+    #[clap(long, global = true, requires = "client-key")]
+    client_cert: Option<PathBuf>,
+
+    /// Private key (PEM) for the client certificate
+    #[clap(long, global = true, requires = "client-cert")]
+    client_key: Option<PathBuf>,
+
+    /// HTTP(S) proxy to connect through (Defaults to the *_PROXY environment variables)
+    #[clap(long, global = true)]
+    pub(crate) proxy: Option<Url>,
+
+    /// Timeout in seconds for the initial sync
+    #[clap(long, global = true)]
+    sync_timeout: Option<u64>,
+
+    /// Fetch the full room timelines and member lists in the initial sync
+    #[clap(long, global = true)]
+    full_sync: bool,
+
+    /// Number of times to retry a failed initial sync
+    #[clap(long, global = true, default_value_t = 0)]
+    sync_retries: u32,
+
+    /// Environment variable holding a passphrase to encrypt the session file with
+    #[clap(long, global = true, value_name = "VARNAME")]
+    session_passphrase_env: Option<String>,
+
+    /// Print what would be sent instead of sending messages or files, other changes are refused
+    #[clap(long, global = true)]
+    pub(crate) dry_run: bool,
+
+    /// Wait and retry when the server reports rate limiting
+    #[clap(
+        long,
+        global = true,
+        action = ArgAction::Set,
+        default_value_t = true,
+        value_name = "BOOL"
+    )]
+    respect_rate_limits: bool,
+
+    /// Login again with the saved User ID if the session has expired (asks for the Password,
+    /// so only if stdin is a terminal)
+    #[clap(long, global = true)]
+    pub(crate) force_relogin: bool,
+}
+
+impl ClientOptions {
+    fn identity(&self) -> Result<Option<reqwest::Identity>> {
+        if let (Some(cert), Some(key)) = (&self.client_cert, &self.client_key) {
+            let mut pem = Self::read_pem(cert)?;
+            pem.push(b'\n');
+            pem.extend(Self::read_pem(key)?);
+            let identity =
+                reqwest::Identity::from_pem(&pem).map_err(|_| Error::InvalidClientCertificate)?;
+            Ok(Some(identity))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Builder for an HTTP client going through the proxy
+    fn proxied_http_client(&self) -> Result<reqwest::ClientBuilder> {
+        let mut http_client = reqwest::Client::builder();
+        if let Some(proxy) = &self.proxy {
+            http_client = http_client
+                .proxy(reqwest::Proxy::all(proxy.as_str()).map_err(|_| Error::InvalidProxy)?);
+        }
+        Ok(http_client)
+    }
+
+    /// HTTP client going through the proxy and presenting the client certificate
+    fn http_client(&self) -> Result<reqwest::Client> {
+        let mut http_client = self.proxied_http_client()?;
+        if let Some(identity) = self.identity()? {
+            http_client = http_client.identity(identity);
+        }
+        http_client
+            .build()
+            .map_err(|_| Error::InvalidClientCertificate)
+    }
+
+    fn session_passphrase(&self) -> Result<Option<String>> {
+        self.session_passphrase_env
+            .as_ref()
+            .map(|var| env::var(var).map_err(|_| Error::MissingEnvVar(var.clone())))
+            .transpose()
+    }
+
+    fn read_pem(path: &Path) -> Result<Vec<u8>> {
+        fs::read(path).map_err(|e| Error::ReadFile(path.to_owned(), e))
+    }
+}
+
+pub struct MatrixClient {
     client: Client,
     session_file: PathBuf,
+    sync_settings: SyncSettings<'static>,
+    sync_retries: u32,
+    dry_run: bool,
+    respect_rate_limits: bool,
+    http_client: reqwest::Client,
 }
 
 impl Deref for MatrixClient {
@@ -103,56 +302,218 @@ impl Deref for MatrixClient {
 }
 
 impl MatrixClient {
-    fn new(client: Client, dirs: &Directories) -> Self {
-        Self {
+    fn new(client: Client, dirs: &Directories, options: &ClientOptions) -> Result<Self> {
+        let mut sync_settings = SyncSettings::new();
+        if let Some(timeout) = options.sync_timeout {
+            sync_settings = sync_settings.timeout(Duration::from_secs(timeout));
+        }
+        if !options.full_sync {
+            // commands only need the room state, so keep the initial sync small
+            let mut filter = FilterDefinition::default();
+            filter.room.timeline.limit = Some(UInt::from(1_u32));
+            filter.room.state.lazy_load_options = LazyLoadOptions::Enabled {
+                include_redundant_members: false,
+            };
+            sync_settings = sync_settings.filter(Filter::FilterDefinition(filter));
+        }
+        Ok(Self {
             client,
             session_file: dirs.session_file.clone(),
+            sync_settings,
+            sync_retries: options.sync_retries,
+            dry_run: options.dry_run,
+            respect_rate_limits: options.respect_rate_limits,
+            http_client: options
+                .proxied_http_client()?
+                .build()
+                .map_err(|_| Error::InvalidProxy)?,
+        })
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Fail for commands that change something but have nothing to print for --dry-run
+    pub fn refuse_dry_run(&self) -> Result {
+        if self.dry_run {
+            return Error::custom("This command can't be used with --dry-run");
+        }
+        Ok(())
+    }
+
+    /// HTTP client for requests outside of Matrix, honouring --proxy
+    ///
+    /// The client certificate is only meant for the homeserver, so it isn't presented here.
+    pub fn http_client(&self) -> &reqwest::Client {
+        &self.http_client
+    }
+
+    pub fn respect_rate_limits(&self) -> bool {
+        self.respect_rate_limits
+    }
+
+    async fn create_client(homeserver: &Homeserver, options: &ClientOptions) -> Result<Client> {
+        let mut builder = match homeserver {
+            Homeserver::Url(url) => Client::builder().homeserver_url(url),
+            Homeserver::ServerName(server_name) => Client::builder().server_name(server_name),
+            Homeserver::UserId(user_id) => Client::builder().server_name(user_id.server_name()),
+        };
+        if options.client_cert.is_some() {
+            builder = builder.http_client(Arc::new(options.http_client()?));
+        } else if let Some(proxy) = &options.proxy {
+            builder = builder.proxy(proxy.as_str());
         }
+        Ok(builder.build().await?)
     }
 
-    async fn create_client(homserver: Url) -> Result<Client> {
-        Ok(Client::new(homserver).await?)
+    /// Make sure the homeserver can be discovered and answers requests
+    pub async fn check_homeserver(homeserver: &Homeserver, options: &ClientOptions) -> Result {
+        let unreachable = |e: Error| Error::Unreachable(homeserver.to_string(), Box::new(e));
+        let client = Self::create_client(homeserver, options)
+            .await
+            .map_err(unreachable)?;
+        client
+            .send(get_supported_versions::Request::new(), None)
+            .await
+            .map_err(|e| unreachable(e.into()))?;
+        Ok(())
     }
 
-    pub(crate) async fn load(dirs: &Directories) -> Result<Self> {
+    pub async fn load(dirs: &Directories, options: &ClientOptions) -> Result<Self> {
         if dirs.session_file.exists() {
-            let session = SessionData::load(&dirs.session_file)?;
+            let passphrase = options.session_passphrase()?;
+            let session = SessionData::load(&dirs.session_file, passphrase.as_deref())?;
 
-            let client = Self::create_client(session.homeserver.clone()).await?;
+            let homeserver = Homeserver::Url(session.homeserver.clone());
+            let client = Self::create_client(&homeserver, options).await?;
             client.restore_login(session.into()).await?;
 
-            let client = Self::new(client, dirs);
-            client.sync_once().await?;
+            let client = Self::new(client, dirs, options)?;
+            client
+                .sync_once()
+                .await
+                .map_err(Error::or_session_expired)?;
             Ok(client)
         } else {
             Err(Error::NotLoggedIn)
         }
     }
 
-    pub(crate) async fn login(
+    /// Replace the saved session with a new login of the same User, once that succeeded
+    pub async fn relogin(
         dirs: &Directories,
-        homeserver: &Url,
+        options: &ClientOptions,
+        password: &str,
+    ) -> Result<Self> {
+        let passphrase = options.session_passphrase()?;
+        let session = SessionData::load(&dirs.session_file, passphrase.as_deref())?;
+        Self::login(
+            dirs,
+            options,
+            &Homeserver::Url(session.homeserver),
+            session.user_id.as_str(),
+            password,
+            None,
+        )
+        .await
+    }
+
+    pub async fn login(
+        dirs: &Directories,
+        options: &ClientOptions,
+        homeserver: &Homeserver,
         username: &str,
         password: &str,
+        device_name: Option<&str>,
     ) -> Result<Self> {
-        let client = Self::create_client(homeserver.clone()).await?;
+        let client = Self::create_client(homeserver, options).await?;
         SessionData::new(
-            homeserver.clone(),
+            client.homeserver().await,
             client
-                .login(username, password, None, Some(crate::APP_NAME))
-                .await?,
+                .login(
+                    username,
+                    password,
+                    None,
+                    Some(device_name.unwrap_or(crate::APP_NAME)),
+                )
+                .await
+                .map_err(|e| match &e {
+                    matrix_sdk::Error::Http(http)
+                        if matches!(http.client_api_error_kind(), Some(ErrorKind::Forbidden)) =>
+                    {
+                        Error::InvalidCredentials
+                    }
+                    _ => e.into(),
+                })?,
         )
-        .save(&dirs.session_file)?;
+        .save(&dirs.session_file, options.session_passphrase()?.as_deref())?;
+
+        let client = Self::new(client, dirs, options)?;
+        client.sync_once().await?;
+        Ok(client)
+    }
+
+    /// Login in the browser, receiving the login token on a localhost callback
+    pub async fn login_sso(
+        dirs: &Directories,
+        options: &ClientOptions,
+        homeserver: &Homeserver,
+        device_name: Option<&str>,
+    ) -> Result<Self> {
+        let client = Self::create_client(homeserver, options).await?;
+        let response = client
+            .login_with_sso(
+                |url| async move {
+                    if webbrowser::open(&url).is_err() {
+                        eprintln!("Open this URL in a browser to login:\n{}", url);
+                    }
+                    Ok(())
+                },
+                None,
+                None,
+                None,
+                Some(device_name.unwrap_or(crate::APP_NAME)),
+                None,
+            )
+            .await?;
+        SessionData::new(client.homeserver().await, response)
+            .save(&dirs.session_file, options.session_passphrase()?.as_deref())?;
+
+        let client = Self::new(client, dirs, options)?;
+        client.sync_once().await?;
+        Ok(client)
+    }
+
+    pub async fn login_with_token(
+        dirs: &Directories,
+        options: &ClientOptions,
+        homeserver: &Homeserver,
+        user_id: OwnedUserId,
+        access_token: String,
+        device_id: OwnedDeviceId,
+    ) -> Result<Self> {
+        let client = Self::create_client(homeserver, options).await?;
+        let session = SessionData {
+            homeserver: client.homeserver().await,
+            access_token,
+            device_id,
+            user_id,
+        };
+        client.restore_login(session.clone().into()).await?;
 
-        let client = Self::new(client, dirs);
+        // only keep the session once the token proved to be valid
+        let client = Self::new(client, dirs, options)?;
         client.sync_once().await?;
+        session.save(&dirs.session_file, options.session_passphrase()?.as_deref())?;
         Ok(client)
     }
 
-    pub(crate) async fn logout(self) -> Result {
+    pub async fn logout(self) -> Result {
         let Self {
             client,
             session_file,
+            ..
         } = self;
 
         // TODO: send logout to server
@@ -162,19 +523,100 @@ impl MatrixClient {
         Ok(())
     }
 
-    pub(crate) async fn sync_once(&self) -> Result {
-        self.client.sync_once(SyncSettings::new()).await?;
-        Ok(())
+    pub async fn sync_once(&self) -> Result {
+        let mut attempt = 0;
+        loop {
+            match self.client.sync_once(self.sync_settings.clone()).await {
+                Ok(_) => return Ok(()),
+                // retrying won't bring a rejected token back
+                Err(matrix_sdk::Error::Http(http)) if is_unknown_token(&http) => {
+                    return Err(Error::SyncFailed(attempt + 1, http.into()))
+                }
+                Err(e) if attempt >= self.sync_retries => {
+                    return Err(Error::SyncFailed(attempt + 1, e))
+                }
+                Err(_) => {
+                    attempt += 1;
+                    time::sleep(Duration::from_secs(attempt.into())).await;
+                }
+            }
+        }
+    }
+
+    /// Run `request`, waiting as long as the server asks whenever it is rate limited
+    pub async fn rate_limited<T, F, Fut>(&self, mut request: F) -> matrix_sdk::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = matrix_sdk::Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match request().await {
+                Err(matrix_sdk::Error::Http(http))
+                    if self.respect_rate_limits && attempt < RATE_LIMIT_ATTEMPTS =>
+                {
+                    match http.client_api_error_kind() {
+                        Some(ErrorKind::LimitExceeded { retry_after_ms }) => {
+                            let delay = retry_after_ms.unwrap_or(Duration::from_secs(1));
+                            tracing::debug!("Rate limited, waiting {:?}", delay);
+                            time::sleep(delay).await;
+                            attempt += 1;
+                        }
+                        _ => return Err(http.into()),
+                    }
+                }
+                result => return result,
+            }
+        }
     }
 
     /*pub(crate) fn room(&self, room_id: &RoomId) -> Result<room::Room> {
         self.get_room(room_id).ok_or(Error::InvalidRoom)
     }*/
 
-    pub(crate) fn joined_room(&self, room_id: &RoomId) -> Result<room::Joined> {
+    pub fn joined_room(&self, room_id: &RoomId) -> Result<room::Joined> {
         self.get_joined_room(room_id).ok_or(Error::InvalidRoom)
     }
 
+    /// Resolve a Room Alias to its Room ID
+    pub async fn resolve_room(&self, room: &RoomOrAliasId) -> Result<OwnedRoomId> {
+        match <&RoomId>::try_from(room) {
+            Ok(room_id) => Ok(room_id.to_owned()),
+            Err(alias) => Ok(self.resolve_room_alias(alias).await?.room_id),
+        }
+    }
+
+    pub async fn joined_room_by_id_or_alias(&self, room: &RoomOrAliasId) -> Result<room::Joined> {
+        self.joined_room(&self.resolve_room(room).await?)
+    }
+
+    /// A joined Room that Messages can be sent to
+    ///
+    /// Without end-to-end encryption the sdk would send plaintext into encrypted Rooms, so those
+    /// are refused.
+    pub fn sendable_room(&self, room_id: &RoomId) -> Result<room::Joined> {
+        let room = self.joined_room(room_id)?;
+        if room.is_encrypted() {
+            return Err(Error::EncryptionNotReady);
+        }
+        Ok(room)
+    }
+
+    pub async fn sendable_room_by_id_or_alias(&self, room: &RoomOrAliasId) -> Result<room::Joined> {
+        self.sendable_room(&self.resolve_room(room).await?)
+    }
+
+    /// Sync until a freshly joined room shows up as joined
+    pub async fn wait_for_joined_room(&self, room_id: &RoomId) -> Result<room::Joined> {
+        for _ in 0..JOIN_SYNC_ATTEMPTS {
+            if let Some(room) = self.get_joined_room(room_id) {
+                return Ok(room);
+            }
+            self.sync_once().await?;
+        }
+        self.joined_room(room_id)
+    }
+
     /*pub(crate) fn invited_room(&self, room_id: &RoomId) -> Result<room::Invited> {
         self.get_invited_room(room_id).ok_or(Error::InvalidRoom)
     }*/
@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use clap::ArgEnum;
+
+use matrix_sdk::ruma::{
+    events::room::message::RoomMessageEventContent, MilliSecondsSinceUnixEpoch, RoomId, UserId,
+};
+
+use serde::Deserialize;
+
+use serde_json::json;
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Suppress prompts, progress bars and other informational output
+pub(crate) fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub(crate) fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// How commands print their results
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum OutputFormat {
+    /// Tab separated text
+    Text,
+
+    /// Newline-delimited JSON objects
+    Json,
+}
+
+/// Print a single message, as read from a Room timeline
+pub(crate) fn print_message(
+    room_id: &RoomId,
+    sender: &UserId,
+    timestamp: MilliSecondsSinceUnixEpoch,
+    content: &RoomMessageEventContent,
+    output: OutputFormat,
+) {
+    let timestamp = timestamp
+        .to_system_time()
+        .map(|ts| humantime::format_rfc3339_seconds(ts).to_string());
+    let body = content.msgtype.body();
+    match output {
+        OutputFormat::Text => println!(
+            "{}\t{}\t{}\t{}",
+            timestamp.as_deref().unwrap_or("-"),
+            room_id,
+            sender,
+            body
+        ),
+        OutputFormat::Json => println!(
+            "{}",
+            json!({
+                "room_id": room_id,
+                "sender": sender,
+                "timestamp": timestamp,
+                "body": body,
+            })
+        ),
+    }
+}
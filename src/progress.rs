@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+use crate::output;
+
+use atty::Stream;
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+const TEMPLATE: &str = "{spinner} Uploading {msg} ({elapsed})";
+
+/// Spinner on stderr while an upload runs, when attached to a terminal
+///
+/// matrix-sdk reads the whole file into memory before sending it and doesn't report how much
+/// of the request was sent, so there are no byte counts to show.
+pub(crate) struct UploadSpinner(ProgressBar);
+
+impl UploadSpinner {
+    pub(crate) fn new(message: String) -> Self {
+        let bar = if atty::is(Stream::Stderr) && !output::quiet() {
+            ProgressBar::new_spinner()
+        } else {
+            ProgressBar::hidden()
+        };
+        bar.set_style(
+            ProgressStyle::with_template(TEMPLATE)
+                .unwrap_or_else(|_| ProgressStyle::default_spinner()),
+        );
+        bar.set_message(message);
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Self(bar)
+    }
+}
+
+impl Drop for UploadSpinner {
+    fn drop(&mut self) {
+        self.0.finish_and_clear();
+    }
+}
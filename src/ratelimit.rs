@@ -0,0 +1,61 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::time::{self, Instant};
+
+/// Rate limit spec of the form `<N>/<DURATION>`, e.g. `5/1s`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RateLimit {
+    count: u32,
+    per: Duration,
+}
+
+impl FromStr for RateLimit {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, per) = s.split_once('/').ok_or("expected <N>/<DURATION>")?;
+        let count = count.trim().parse().map_err(|_| "invalid count")?;
+        let per = humantime::parse_duration(per.trim()).map_err(|_| "invalid duration")?;
+        if count == 0 || per.is_zero() {
+            return Err("count and duration must be greater than zero");
+        }
+        Ok(Self { count, per })
+    }
+}
+
+/// Token bucket gating outgoing sends
+pub(crate) struct RateLimiter {
+    limit: Option<RateLimit>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: Option<RateLimit>) -> Self {
+        Self {
+            limit,
+            tokens: limit.map_or(0.0, |l| l.count.into()),
+            last: Instant::now(),
+        }
+    }
+
+    pub(crate) async fn acquire(&mut self) {
+        if let Some(limit) = self.limit {
+            let rate = f64::from(limit.count) / limit.per.as_secs_f64();
+            self.refill(limit, rate);
+            if self.tokens < 1.0 {
+                time::sleep(Duration::from_secs_f64((1.0 - self.tokens) / rate)).await;
+                self.refill(limit, rate);
+            }
+            self.tokens -= 1.0;
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, rate: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(limit.count.into());
+        self.last = now;
+    }
+}
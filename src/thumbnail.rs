@@ -0,0 +1,73 @@
+use std::path::Path;
+
+use matrix_sdk::attachment::{AttachmentInfo, BaseImageInfo, BaseThumbnailInfo, Thumbnail};
+
+use mime::Mime;
+
+#[cfg(feature = "thumbnails")]
+const THUMBNAIL_SIZE: u32 = 800;
+
+/// Dimensions and a PNG thumbnail of an image attachment
+#[cfg_attr(not(feature = "thumbnails"), allow(dead_code))]
+pub(crate) struct ImageThumbnail {
+    info: BaseImageInfo,
+    thumbnail_info: BaseThumbnailInfo,
+    data: Vec<u8>,
+}
+
+impl ImageThumbnail {
+    /// Decode the image at `path`, returning `None` for anything that can't (or shouldn't) be
+    /// previewed, like animated GIFs or unsupported formats
+    #[cfg(feature = "thumbnails")]
+    pub(crate) fn new(path: &Path, mime: &Mime) -> Option<Self> {
+        use std::io::Cursor;
+
+        use image::ImageOutputFormat;
+        use matrix_sdk::ruma::UInt;
+
+        if mime.type_() != mime::IMAGE || *mime == mime::IMAGE_GIF {
+            return None;
+        }
+        let image = image::open(path).ok()?;
+        let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+        let mut data = Vec::new();
+        thumbnail
+            .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+            .ok()?;
+        Some(Self {
+            info: BaseImageInfo {
+                height: Some(image.height().into()),
+                width: Some(image.width().into()),
+                size: path.metadata().ok().and_then(|m| UInt::new(m.len())),
+                blurhash: None,
+            },
+            thumbnail_info: BaseThumbnailInfo {
+                height: Some(thumbnail.height().into()),
+                width: Some(thumbnail.width().into()),
+                size: UInt::new(data.len() as u64),
+            },
+            data,
+        })
+    }
+
+    #[cfg(not(feature = "thumbnails"))]
+    pub(crate) fn new(_path: &Path, _mime: &Mime) -> Option<Self> {
+        None
+    }
+
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub(crate) fn info(&self) -> AttachmentInfo {
+        AttachmentInfo::Image(self.info.clone())
+    }
+
+    pub(crate) fn thumbnail<'a>(&self, reader: &'a mut &'a [u8]) -> Thumbnail<'a, &'a [u8]> {
+        Thumbnail {
+            reader,
+            content_type: &mime::IMAGE_PNG,
+            info: Some(self.thumbnail_info.clone()),
+        }
+    }
+}